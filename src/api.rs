@@ -0,0 +1,391 @@
+use crate::control::{AuthPrompt, InFlight, CONTROL};
+use crate::overrides::{get_overrides, set_overrides, VideoOverrides};
+use crate::prelude::*;
+use crate::report::{create_report, Report};
+use crate::UPLOADER_CONF;
+use axum::extract::{Path, Request, State};
+use axum::http::{header, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Redirect, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+use twba_local_db::prelude::*;
+use twba_local_db::re_exports::sea_orm::{
+    ActiveModelTrait, ActiveValue, ColumnTrait, DatabaseConnection, EntityTrait, IntoActiveModel,
+    Order, QueryFilter, QueryOrder,
+};
+
+#[derive(Debug, Clone)]
+struct ApiState {
+    db: DatabaseConnection,
+    /// Required by every endpoint but the health checks, if set
+    token: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct QueuedVideo {
+    id: i32,
+    user_id: i32,
+    name: String,
+    status: String,
+    part_count: i32,
+    fail_count: i32,
+    cancelled: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct Progress {
+    in_flight: Option<InFlight>,
+    pending_auth_users: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AuthCode {
+    code: String,
+}
+
+type ApiResult<T> = StdResult<T, (StatusCode, String)>;
+
+pub(crate) async fn bind(address: &str) -> Result<TcpListener> {
+    if UPLOADER_CONF.daemon.api_token.is_none() && !is_loopback_address(address) {
+        return Err(UploaderError::ApiTokenRequired(address.to_string()));
+    }
+    info!("starting http api on: {}", address);
+    let listener = TcpListener::bind(address)
+        .await
+        .map_err(UploaderError::HttpServer)?;
+    CONTROL.set_http_enabled(true);
+    Ok(listener)
+}
+
+pub(crate) async fn serve(listener: TcpListener, db: DatabaseConnection) -> Result<()> {
+    let state = ApiState {
+        db,
+        token: UPLOADER_CONF.daemon.api_token.clone(),
+    };
+    let protected = Router::new()
+        .route("/queue", get(queue))
+        .route("/progress", get(progress))
+        .route("/report", get(report))
+        .route("/run", post(run))
+        .route("/videos/:id/retry", post(retry_video))
+        .route("/videos/:id/cancel", post(cancel_video))
+        .route(
+            "/videos/:id/overrides",
+            get(get_video_overrides).put(set_video_overrides),
+        )
+        .route("/auth/:user/code", post(submit_auth_code))
+        .route("/auth/:user/cancel", post(cancel_auth))
+        .route("/auth/:user/start", get(start_auth))
+        .route_layer(middleware::from_fn_with_state(state.clone(), require_token));
+    let app = Router::new()
+        .route("/health", get(health))
+        .route("/ready", get(ready))
+        .merge(protected)
+        .with_state(state);
+    let result = axum::serve(listener, app).await;
+    CONTROL.set_http_enabled(false);
+    result.map_err(UploaderError::HttpServer)
+}
+
+/// Rejects requests without the configured token
+async fn require_token(State(state): State<ApiState>, request: Request, next: Next) -> Response {
+    let Some(expected) = &state.token else {
+        return next.run(request).await;
+    };
+    let bearer = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    let query = request.uri().query().and_then(token_from_query);
+    if bearer
+        .or(query)
+        .is_some_and(|given| tokens_match(expected, given))
+    {
+        next.run(request).await
+    } else {
+        (StatusCode::UNAUTHORIZED, "missing or wrong api token").into_response()
+    }
+}
+
+fn token_from_query(query: &str) -> Option<&str> {
+    query
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("token="))
+}
+
+/// Compares the tokens without stopping at the first difference
+fn tokens_match(expected: &str, given: &str) -> bool {
+    expected.len() == given.len()
+        && expected
+            .bytes()
+            .zip(given.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+fn is_loopback_address(address: &str) -> bool {
+    match address.parse::<SocketAddr>() {
+        Ok(address) => address.ip().is_loopback(),
+        Err(_) => address
+            .rsplit_once(':')
+            .is_some_and(|(host, _)| host == "localhost"),
+    }
+}
+
+async fn health() -> &'static str {
+    "ok"
+}
+
+async fn ready() -> (StatusCode, &'static str) {
+    if CONTROL.is_ready() {
+        (StatusCode::OK, "ready")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "not ready")
+    }
+}
+
+async fn queue(State(state): State<ApiState>) -> ApiResult<Json<Vec<QueuedVideo>>> {
+    let videos = Videos::find()
+        .filter(VideosColumn::Status.is_in([
+            Status::Split,
+            Status::Uploading,
+            Status::PartiallyUploaded,
+        ]))
+        .order_by(VideosColumn::CreatedAt, Order::Asc)
+        .all(&state.db)
+        .await
+        .map_err(internal_error)?;
+    let videos = videos
+        .into_iter()
+        .map(|video| QueuedVideo {
+            cancelled: CONTROL.is_cancelled(video.id),
+            id: video.id,
+            user_id: video.user_id,
+            name: video.name,
+            status: format!("{:?}", video.status),
+            part_count: video.part_count,
+            fail_count: video.fail_count,
+        })
+        .collect();
+    Ok(Json(videos))
+}
+
+async fn progress() -> Json<Progress> {
+    Json(Progress {
+        in_flight: CONTROL.in_flight(),
+        pending_auth_users: CONTROL.pending_auth_users(),
+    })
+}
+
+async fn report(State(state): State<ApiState>) -> ApiResult<Json<Report>> {
+    let report = create_report(&state.db).await.map_err(internal_error)?;
+    Ok(Json(report))
+}
+
+async fn run() -> StatusCode {
+    info!("run triggered through the http api");
+    CONTROL.trigger_run();
+    StatusCode::ACCEPTED
+}
+
+/// Why a video can not be retried, if it can not.
+///
+/// Only videos in the upload stage can be retried, a failed upload keeps its
+/// status and only counts up its fails. Anything before that still needs
+/// the downloader or the splitter, so the part files do not exist yet.
+fn retry_conflict(status: &Status, in_flight: bool) -> Option<&'static str> {
+    match status {
+        _ if in_flight => Some("the video is uploading right now"),
+        Status::Split | Status::Uploading | Status::PartiallyUploaded => None,
+        Status::Uploaded => Some("the video is already uploaded"),
+        _ => Some("the video is not split yet, only uploads can be retried"),
+    }
+}
+
+/// Resets the fail count of a video and queues it again
+async fn retry_video(State(state): State<ApiState>, Path(id): Path<i32>) -> ApiResult<StatusCode> {
+    let video = find_video(&state.db, id).await?;
+    let in_flight = CONTROL
+        .in_flight()
+        .is_some_and(|in_flight| in_flight.video_id == id);
+    if let Some(conflict) = retry_conflict(&video.status, in_flight) {
+        return Err((StatusCode::CONFLICT, conflict.to_string()));
+    }
+    let uploaded_parts = VideoUpload::find()
+        .filter(VideoUploadColumn::VideoId.eq(id))
+        .all(&state.db)
+        .await
+        .map_err(internal_error)?
+        .iter()
+        .any(|upload| upload.youtube_video_id.is_some());
+    let status = if uploaded_parts {
+        Status::PartiallyUploaded
+    } else {
+        Status::Split
+    };
+    info!("retrying video {} with status {:?}", id, status);
+
+    let mut video = video.into_active_model();
+    video.status = ActiveValue::Set(status);
+    video.fail_count = ActiveValue::Set(0);
+    video.update(&state.db).await.map_err(internal_error)?;
+    CONTROL.uncancel(id);
+    CONTROL.trigger_run();
+    Ok(StatusCode::ACCEPTED)
+}
+
+async fn cancel_video(State(state): State<ApiState>, Path(id): Path<i32>) -> ApiResult<StatusCode> {
+    find_video(&state.db, id).await?;
+    info!("cancelling video {}", id);
+    CONTROL.cancel(id);
+    Ok(StatusCode::ACCEPTED)
+}
+
+async fn get_video_overrides(
+    State(state): State<ApiState>,
+    Path(id): Path<i32>,
+) -> ApiResult<Json<VideoOverrides>> {
+    let video = find_video(&state.db, id).await?;
+    let overrides = get_overrides(&state.db, &video)
+        .await
+        .map_err(internal_error)?;
+    Ok(Json(overrides))
+}
+
+/// Replaces all overrides of the video, fields that are not set are removed
+async fn set_video_overrides(
+    State(state): State<ApiState>,
+    Path(id): Path<i32>,
+    Json(body): Json<VideoOverrides>,
+) -> ApiResult<StatusCode> {
+    find_video(&state.db, id).await?;
+    set_overrides(&state.db, id, &body)
+        .await
+        .map_err(internal_error)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn submit_auth_code(
+    Path(user): Path<String>,
+    Json(body): Json<AuthCode>,
+) -> ApiResult<StatusCode> {
+    if CONTROL.submit_auth_code(&user, body.code) {
+        info!("received auth code for user: {}", user);
+        Ok(StatusCode::ACCEPTED)
+    } else {
+        Err((
+            StatusCode::NOT_FOUND,
+            format!("no authentication is waiting for user: {}", user),
+        ))
+    }
+}
+
+async fn cancel_auth(Path(user): Path<String>) -> ApiResult<StatusCode> {
+    if CONTROL.cancel_auth(&user) {
+        info!("cancelled authentication for user: {}", user);
+        Ok(StatusCode::ACCEPTED)
+    } else {
+        Err((
+            StatusCode::NOT_FOUND,
+            format!("no authentication is waiting for user: {}", user),
+        ))
+    }
+}
+
+/// How long to wait for the auth flow to present its url
+const AUTH_PROMPT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Starts a new authentication for the user and sends the browser to the
+/// page where the user authenticates.
+///
+/// This is the link the token health alerts point to.
+async fn start_auth(Path(user): Path<String>) -> Response {
+    info!("authentication requested for user: {}", user);
+    if CONTROL.auth_prompt(&user).is_none() {
+        CONTROL.request_reauth(&user);
+    }
+    let deadline = Instant::now() + AUTH_PROMPT_TIMEOUT;
+    while Instant::now() < deadline {
+        match CONTROL.auth_prompt(&user) {
+            Some(AuthPrompt {
+                url,
+                user_code: None,
+            }) => return Redirect::to(&url).into_response(),
+            Some(AuthPrompt {
+                url,
+                user_code: Some(code),
+            }) => {
+                return format!("Open {} and enter the code {}", url, code).into_response();
+            }
+            None => tokio::time::sleep(Duration::from_millis(500)).await,
+        }
+    }
+    (
+        StatusCode::ACCEPTED,
+        format!(
+            "No authentication was needed for {} yet, the token might still work. \
+            Check the notifications or try again later.",
+            user
+        ),
+    )
+        .into_response()
+}
+
+async fn find_video(db: &DatabaseConnection, id: i32) -> ApiResult<VideosModel> {
+    Videos::find_by_id(id)
+        .one(db)
+        .await
+        .map_err(internal_error)?
+        .ok_or((StatusCode::NOT_FOUND, format!("unknown video: {}", id)))
+}
+
+fn internal_error(e: impl std::error::Error) -> (StatusCode, String) {
+    error!("error in http api: {}", e);
+    (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_tokens_match() {
+        assert!(tokens_match("secret", "secret"));
+        assert!(!tokens_match("secret", "secreT"));
+        assert!(!tokens_match("secret", "secret2"));
+        assert!(!tokens_match("secret", ""));
+    }
+
+    #[test]
+    fn test_token_from_query() {
+        assert_eq!(Some("abc"), token_from_query("token=abc"));
+        assert_eq!(Some("abc"), token_from_query("x=1&token=abc"));
+        assert_eq!(None, token_from_query("tokens=abc"));
+    }
+
+    #[test]
+    fn test_is_loopback_address() {
+        assert!(is_loopback_address("127.0.0.1:8080"));
+        assert!(is_loopback_address("[::1]:8080"));
+        assert!(is_loopback_address("localhost:8080"));
+        assert!(!is_loopback_address("0.0.0.0:8080"));
+        assert!(!is_loopback_address("example.com:8080"));
+    }
+
+    #[test]
+    fn test_retry_conflict() {
+        assert!(retry_conflict(&Status::Split, false).is_none());
+        assert!(retry_conflict(&Status::Uploading, false).is_none());
+        assert!(retry_conflict(&Status::Uploading, true).is_some());
+        assert!(retry_conflict(&Status::Uploaded, false).is_some());
+        assert!(retry_conflict(&Status::PartiallyUploaded, false).is_none());
+        assert!(retry_conflict(&Status::Downloading, false).is_some());
+        assert!(retry_conflict(&Status::Splitting, false).is_some());
+    }
+}
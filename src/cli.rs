@@ -0,0 +1,94 @@
+use clap::{Parser, Subcommand};
+
+#[derive(Debug, Parser)]
+#[command(version, about)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Upload all videos that are ready (this is the default)
+    Upload,
+    /// Keep running and upload in intervals, with the optional http api
+    Daemon,
+    /// Show the queue and channel status of all users
+    Report {
+        /// Print the report as json
+        #[arg(long)]
+        json: bool,
+    },
+    /// Refresh the cached tokens of all users and report their health
+    TokenHealth {
+        /// Print the result as json
+        #[arg(long)]
+        json: bool,
+    },
+    /// Encrypt the plaintext token caches of all users in place
+    EncryptTokens {
+        /// Only show which caches would be encrypted
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Add a user, authenticate them and check that their uploads would work
+    AddUser {
+        /// The id of the twitch channel
+        twitch_id: String,
+        /// The name of the twitch channel
+        twitch_name: String,
+        /// The id of the youtube channel the videos are uploaded to
+        youtube_id: String,
+        /// The timezone of the streamer, as an offset like `+02:00`
+        #[arg(long, default_value = "+00:00")]
+        timezone: String,
+        /// The length of the parts a stream is split into, in seconds
+        #[arg(long, default_value_t = 2 * 60 * 60)]
+        target_duration: i32,
+        /// The longest part that may be uploaded, in seconds
+        #[arg(long, default_value_t = 12 * 60 * 60)]
+        max_duration: i32,
+    },
+    /// Revoke the token of a user, delete it and deactivate the user
+    RemoveUser {
+        /// The id of the user in the database
+        user_id: i32,
+        /// Do not ask for confirmation
+        #[arg(long, short)]
+        yes: bool,
+    },
+    /// Set the title, description, tags or playlist name of a video by hand.
+    ///
+    /// Title, description and playlist name are templates like the configured ones.
+    /// Use `{{ part_ident }}` in the title to tell the parts apart.
+    SetOverrides {
+        /// The id of the video in the database
+        video_id: i32,
+        #[arg(long)]
+        title: Option<String>,
+        #[arg(long)]
+        description: Option<String>,
+        /// The tags, separated by commas
+        #[arg(long, value_delimiter = ',')]
+        tags: Option<Vec<String>>,
+        #[arg(long)]
+        playlist_name: Option<String>,
+        /// Remove the existing overrides before setting the given ones
+        #[arg(long)]
+        clear: bool,
+    },
+    /// Delete everything a failed video left on YouTube and reset it for a fresh upload
+    Rollback {
+        /// The id of the video in the database
+        video_id: i32,
+        /// Only show what would be deleted
+        #[arg(long)]
+        dry_run: bool,
+        /// Do not ask for confirmation
+        #[arg(long, short)]
+        yes: bool,
+        /// Also roll back a video that finished uploading
+        #[arg(long)]
+        force: bool,
+    },
+}
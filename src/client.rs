@@ -1,40 +1,77 @@
+use crate::client::budget::{BudgetLimit, RunBudget};
+use crate::client::data::stream_metadata::StreamMetadata;
 use crate::client::data::VideoData;
-use crate::client::data::{create_youtube_description, create_youtube_title};
+use crate::client::data::{create_youtube_description, create_youtube_tags, create_youtube_title};
+pub(crate) use crate::client::users::NewUser;
+pub(crate) use crate::client::youtube::quota_cost;
+use crate::client::youtube::SHORT_UPLOAD_MAX_SECONDS;
+pub(crate) use crate::client::youtube::{
+    check_cached_token, encrypt_token_cache, validate_redirect_uris, EncryptOutcome, TokenCheck,
+};
+use crate::config::YoutubeFeature;
+use crate::control::{InFlight, CONTROL};
 use crate::prelude::*;
-use crate::CONF;
+use crate::{overrides, store};
+use crate::{CONF, UPLOADER_CONF};
 use data::Location;
-use google_youtube3::api::enums::{PlaylistStatusPrivacyStatusEnum, VideoStatusPrivacyStatusEnum};
-use google_youtube3::api::Scope;
-use lazy_static::lazy_static;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::ffi::OsStr;
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::fs;
+use tokio::task::JoinHandle;
 use tracing::instrument;
 use twba_local_db::entities::video_upload::UploadStatus;
 use twba_local_db::prelude::*;
 use twba_local_db::re_exports::sea_orm::{
     ActiveModelTrait, ActiveValue, ColumnTrait, DatabaseConnection, EntityTrait, IntoActiveModel,
-    Order, QueryFilter, QueryOrder, QuerySelect,
+    Order, PaginatorTrait, QueryFilter, QueryOrder, QuerySelect,
 };
 
+mod budget;
+mod clients;
 pub(crate) mod data;
+mod part_durations;
+mod rollback;
+mod users;
 mod youtube;
 
-lazy_static! {
-    static ref YOUTUBE_DEFAULT_SCOPES: Vec<Scope> =
-        vec![Scope::Upload, Scope::Readonly, Scope::Full];
-}
-#[derive(Debug)]
+/// Uploads the videos of all users.
+///
+/// This is cheap to clone, all clones share the same clients.
+#[derive(Debug, Clone)]
 pub struct UploaderClient {
     db: DatabaseConnection,
-    youtube_clients: HashMap<String, youtube::YoutubeClient>,
+    /// The clients of all users that are authenticated, by user id
+    youtube_clients: Arc<Mutex<HashMap<i32, Arc<youtube::YoutubeClient>>>>,
+    /// The users that are authenticating in the background
+    waiting_for_auth: Arc<Mutex<HashSet<i32>>>,
+    /// The background authentications, so a single run can wait for them
+    auth_tasks: Arc<Mutex<Vec<JoinHandle<()>>>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum VideoUploadOutcome {
+    Finished,
+    /// The run budget was used up before all parts were uploaded.
+    ///
+    /// The video is left in a state that the next run can resume from.
+    BudgetExhausted(BudgetLimit),
+    /// The video was cancelled through the http api
+    Cancelled,
+    /// The user of the video has to authenticate first
+    WaitingForAuth,
+    /// The parts are too long for a channel without long uploads
+    LongUploadsNotAllowed,
+    /// The category of the video can not be used in the region of the channel
+    InvalidCategory(u32),
 }
 
 impl UploaderClient {
     #[tracing::instrument(skip(self))]
     pub(crate) async fn upload_videos(&self) -> Result<()> {
         let videos = Videos::find()
-            .filter(VideosColumn::Status.eq(Status::Split))
+            .filter(VideosColumn::Status.is_in([Status::Split, Status::PartiallyUploaded]))
             .order_by(VideosColumn::CreatedAt, Order::Asc)
             .limit(CONF.max_items_to_process)
             .all(&self.db)
@@ -42,11 +79,58 @@ impl UploaderClient {
         let count = videos.len();
         info!("got {} videos to upload", count);
 
+        let mut budget = RunBudget::new(&UPLOADER_CONF.budget);
         for video in videos {
-            match self.upload_video(&video).await {
-                Ok(_) => {
+            if CONTROL.is_cancelled(video.id) {
+                info!("skipping cancelled video: {}", video.id);
+                continue;
+            }
+            let outcome = self.upload_video(&video, &mut budget).await;
+            CONTROL.set_in_flight(None);
+            match outcome {
+                Ok(VideoUploadOutcome::Finished) => {
                     info!("Uploaded video: {}: {}", video.id, video.name);
                 }
+                Ok(VideoUploadOutcome::BudgetExhausted(limit)) => {
+                    info!(
+                        "{} reached, stopping this run. Video {} will be resumed on the next run",
+                        limit, video.id
+                    );
+                    break;
+                }
+                Ok(VideoUploadOutcome::Cancelled) => {
+                    info!("Cancelled video: {}: {}", video.id, video.name);
+                }
+                Ok(VideoUploadOutcome::WaitingForAuth) => {
+                    info!(
+                        "Skipped video: {}: {}, its user has to authenticate first",
+                        video.id, video.name
+                    );
+                }
+                Ok(VideoUploadOutcome::LongUploadsNotAllowed) => {
+                    warn!(
+                        "Skipped video: {}: {}, its parts are too long for the channel",
+                        video.id, video.name
+                    );
+                }
+                Ok(VideoUploadOutcome::InvalidCategory(category)) => {
+                    warn!(
+                        "Skipped video: {}: {}, its category {} is not valid for the channel",
+                        video.id, video.name, category
+                    );
+                }
+                Err(UploaderError::InsufficientScope(feature)) => {
+                    warn!(
+                        "Skipped video: {}: {}, its user has to grant access for {}",
+                        video.id, video.name, feature
+                    );
+                    if let Err(e) = self.request_reconsent(&video, feature).await {
+                        error!(
+                            "could not request consent for {} of video {}: {}",
+                            feature, video.id, e
+                        );
+                    }
+                }
                 Err(e) => {
                     error!("Error while uploading the video: {}: {}", video.id, e);
 
@@ -62,6 +146,8 @@ impl UploaderClient {
                         "{}: {}\n\n{}",
                         fail_count, e, previous_fails
                     )));
+                    let video = video.update(&self.db).await?;
+                    store::set_video_failure(&self.db, video.id).await?;
                 }
             }
         }
@@ -69,53 +155,186 @@ impl UploaderClient {
         Ok(())
     }
 
-    #[instrument(skip(self, video), fields(id=video.id))]
-    async fn upload_video(&self, video: &VideosModel) -> Result<()> {
+    #[instrument(skip(self, video, budget), fields(id=video.id))]
+    async fn upload_video(
+        &self,
+        video: &VideosModel,
+        budget: &mut RunBudget,
+    ) -> Result<VideoUploadOutcome> {
         let video_id = video.id;
         trace!("uploading video: {:?}", video);
-        let client_for_video = self.get_client_for_video(video)?;
+        if let Some(limit) = budget.exhausted() {
+            return Ok(VideoUploadOutcome::BudgetExhausted(limit));
+        }
+        let user = self.get_user_for_video(video).await?;
+        let Some(client_for_video) = self.get_client_for_user(&user).await? else {
+            self.remind_reauth(&user).await?;
+            return Ok(VideoUploadOutcome::WaitingForAuth);
+        };
+        let metadata = UPLOADER_CONF.metadata_for(user.id);
+        let stream = StreamMetadata::load(video.id);
+        let category = metadata.category_for_game(stream.game.as_deref());
+        self.check_categories(&user, &client_for_video).await;
+        if !client_for_video.is_category_valid(category) {
+            return Ok(VideoUploadOutcome::InvalidCategory(category));
+        }
 
-        self.set_video_status_on_db(video, Status::Uploading)
+        let existing_uploads = VideoUpload::find()
+            .filter(VideoUploadColumn::VideoId.eq(video_id))
+            .all(&self.db)
             .await?;
+        let uploaded_parts: HashSet<usize> = existing_uploads
+            .iter()
+            .filter(|upload| upload.upload_status == UploadStatus::Uploaded)
+            .map(|upload| upload.part as usize)
+            .collect();
+        if !uploaded_parts.is_empty() {
+            info!(
+                "resuming video {}: {} of {} parts are already uploaded",
+                video_id,
+                uploaded_parts.len(),
+                video.part_count
+            );
+        }
 
         let part_count = video.part_count;
         let parts_folder_path = Path::new(&CONF.download_folder_path).join(video_id.to_string());
-        let parts = get_part_files(&parts_folder_path, part_count).await?;
-        let user = Users::find_by_id(video.user_id)
-            .one(&self.db)
-            .await?
-            .ok_or(UploaderError::UnknownUser(video.user_id))?;
+        let parts = get_part_files(&parts_folder_path, part_count, &uploaded_parts).await?;
+
+        let part_durations = self.get_part_durations(video, &parts, &stream).await;
+        let part_numbers: Vec<usize> = parts.iter().map(|(_, part)| *part).collect();
+        if longest_part_seconds(video, &user, &part_durations, &part_numbers)
+            > SHORT_UPLOAD_MAX_SECONDS
+            && !self
+                .refresh_long_uploads(&user, &client_for_video)
+                .await
+                .allows_long_uploads()
+        {
+            return Ok(VideoUploadOutcome::LongUploadsNotAllowed);
+        }
+
+        self.set_video_status_on_db(video, Status::Uploading)
+            .await?;
 
-        let tags = vec![];
+        let overrides = overrides::get_overrides(&self.db, video).await?;
+        if !overrides.is_empty() {
+            debug!("using the overrides of video {}", video_id);
+        }
         let all_parts_data = VideoData {
-            video_tags: tags,
-            video_category: 22,
-            video_privacy: VideoStatusPrivacyStatusEnum::Private,
-            playlist_privacy: PlaylistStatusPrivacyStatusEnum::Private,
-            playlist_description: create_youtube_description(video, &user, Location::Playlist)?,
-            playlist_title: create_youtube_title(video, &user, Location::Playlist)?,
+            video_tags: create_youtube_tags(&user, &overrides, &stream),
+            video_category: category,
+            video_privacy: data::video_privacy(metadata.privacy),
+            made_for_kids: metadata.made_for_kids,
+            playlist_privacy: data::playlist_privacy(metadata.privacy),
+            playlist_description: create_youtube_description(
+                video,
+                &user,
+                Location::Playlist,
+                &overrides,
+                &part_durations,
+                &stream,
+            )?,
+            playlist_title: create_youtube_title(
+                video,
+                &user,
+                Location::Playlist,
+                &overrides,
+                &part_durations,
+                &stream,
+            )?,
             //The rest of the fields are filled in the loop
             part_number: 0,
             video_title: "".to_string(),
             video_description: "".to_string(),
         };
-        let playlist_id = client_for_video.create_playlist(&all_parts_data).await?;
-        self.set_playlist_id_for_video(video, playlist_id.clone())
-            .await?;
+        let use_playlists = UPLOADER_CONF
+            .auth
+            .has_feature(&user.youtube_id, YoutubeFeature::Playlists);
+        let playlist_id = match &video.youtube_playlist_id {
+            Some(playlist_id) => {
+                debug!("reusing existing playlist: {}", playlist_id);
+                Some(playlist_id.clone())
+            }
+            None if use_playlists => {
+                let playlist = client_for_video.create_playlist(&all_parts_data).await;
+                self.record_quota(video.user_id, quota_cost::PLAYLIST_INSERT)
+                    .await;
+                let playlist_id = playlist?;
+                self.set_playlist_id_for_video(video, playlist_id.clone())
+                    .await?;
+                Some(playlist_id)
+            }
+            None => {
+                debug!("playlists are disabled for user {}", user.id);
+                None
+            }
+        };
 
+        // so the parts can link to a playlist that was just created
+        let video = &VideosModel {
+            youtube_playlist_id: playlist_id.clone(),
+            ..video.clone()
+        };
+        let mut uploaded_any = !uploaded_parts.is_empty();
         for (part, part_number) in parts {
-            let mut video_upload = self
-                .insert_video_upload(video_id, part_number)
-                .await?
-                .into_active_model();
+            let stop = if CONTROL.is_cancelled(video_id) {
+                Some(VideoUploadOutcome::Cancelled)
+            } else {
+                budget.exhausted().map(VideoUploadOutcome::BudgetExhausted)
+            };
+            if let Some(outcome) = stop {
+                info!(
+                    "stopping before part {} of video {}: {:?}",
+                    part_number, video_id, outcome
+                );
+                let status = if uploaded_any {
+                    Status::PartiallyUploaded
+                } else {
+                    video.status.clone()
+                };
+                self.set_video_status_on_db(video, status).await?;
+                return Ok(outcome);
+            }
+            CONTROL.set_in_flight(Some(InFlight {
+                video_id,
+                video_name: video.name.clone(),
+                part: part_number,
+                part_count,
+                started_at: chrono::Utc::now().to_rfc3339(),
+            }));
+            let part_size = fs::metadata(&part)
+                .await
+                .map_err(UploaderError::OpenPartFile)?
+                .len();
+
+            let existing_upload = existing_uploads
+                .iter()
+                .find(|upload| upload.part as usize == part_number);
+            let mut video_upload = match existing_upload {
+                Some(upload) => upload.clone().into_active_model(),
+                None => self
+                    .insert_video_upload(video_id, part_number)
+                    .await?
+                    .into_active_model(),
+            };
 
             let data = VideoData {
                 part_number,
-                video_title: create_youtube_title(video, &user, Location::Video(part_number))?,
+                video_title: create_youtube_title(
+                    video,
+                    &user,
+                    Location::Video(part_number),
+                    &overrides,
+                    &part_durations,
+                    &stream,
+                )?,
                 video_description: create_youtube_description(
                     video,
                     &user,
                     Location::Video(part_number),
+                    &overrides,
+                    &part_durations,
+                    &stream,
                 )?,
                 ..all_parts_data.clone()
             };
@@ -126,13 +345,24 @@ impl UploaderClient {
                 part.display()
             );
             let upload = client_for_video.upload_video_part(&part, data).await;
+            budget.record_part(
+                part_size,
+                part_media_seconds(video, part_number, &part_durations),
+            );
+            self.record_quota(video.user_id, quota_cost::VIDEO_INSERT)
+                .await;
             match upload {
                 Ok(uploaded_video_id) => {
                     info!("uploaded part: {}", part.display());
                     dbg!(&uploaded_video_id);
-                    client_for_video
-                        .add_video_to_playlist(uploaded_video_id.clone(), playlist_id.clone())
-                        .await?;
+                    if let Some(playlist_id) = &playlist_id {
+                        let added = client_for_video
+                            .add_video_to_playlist(uploaded_video_id.clone(), playlist_id.clone())
+                            .await;
+                        self.record_quota(video.user_id, quota_cost::PLAYLIST_ITEM_INSERT)
+                            .await;
+                        added?;
+                    }
                     video_upload.upload_status = ActiveValue::Set(UploadStatus::Uploaded);
                     video_upload.youtube_video_id = ActiveValue::Set(Some(uploaded_video_id));
                     video_upload.update(&self.db).await?;
@@ -142,6 +372,7 @@ impl UploaderClient {
                     return Err(e);
                 }
             }
+            uploaded_any = true;
 
             self.set_video_status_on_db(video, Status::PartiallyUploaded)
                 .await?;
@@ -149,7 +380,15 @@ impl UploaderClient {
 
         info!("all parts uploaded for video: {}", video_id);
         self.set_video_status_on_db(video, Status::Uploaded).await?;
-        Ok(())
+        store::set_last_upload(&self.db, video.user_id, video_id).await?;
+        Ok(VideoUploadOutcome::Finished)
+    }
+
+    /// Records used quota. Failing to do so is logged but not fatal
+    async fn record_quota(&self, user_id: i32, units: i64) {
+        if let Err(e) = store::add_quota_usage(&self.db, user_id, units).await {
+            warn!("could not record quota usage for user {}: {}", user_id, e);
+        }
     }
 
     async fn insert_video_upload(
@@ -191,6 +430,21 @@ impl UploaderClient {
             .map_err(UploaderError::SaveVideoStatus)?;
         Ok(())
     }
+    /// Sets the status of the video so it is picked up again on the next run
+    async fn reset_for_resume(&self, video: &VideosModel) -> Result<()> {
+        let uploaded_any = VideoUpload::find()
+            .filter(VideoUploadColumn::VideoId.eq(video.id))
+            .filter(VideoUploadColumn::UploadStatus.eq(UploadStatus::Uploaded))
+            .count(&self.db)
+            .await?
+            > 0;
+        let status = if uploaded_any {
+            Status::PartiallyUploaded
+        } else {
+            Status::Split
+        };
+        self.set_video_status_on_db(video, status).await
+    }
     #[tracing::instrument(skip(self, video_upload))]
     async fn set_video_upload_status_on_db(
         &self,
@@ -211,17 +465,67 @@ impl UploaderClient {
             .map_err(UploaderError::SaveVideoStatus)?;
         Ok(())
     }
-    fn get_client_for_video(&self, video: &VideosModel) -> Result<&youtube::YoutubeClient> {
-        let c = self
-            .youtube_clients
-            .get(&video.user_id.to_string())
-            .ok_or(UploaderError::NoClient(video.user_id))?;
-        Ok(c)
+    async fn get_user_for_video(&self, video: &VideosModel) -> Result<UsersModel> {
+        Users::find_by_id(video.user_id)
+            .one(&self.db)
+            .await?
+            .ok_or(UploaderError::UnknownUser(video.user_id))
     }
 }
 
-async fn get_part_files(folder_path: &Path, count: i32) -> Result<Vec<(PathBuf, usize)>> {
+/// Estimates the media duration of a single part by splitting the video evenly
+fn estimate_part_seconds(video: &VideosModel) -> u64 {
+    let part_count = video.part_count.max(1) as u64;
+    (video.duration.max(0) as u64).div_ceil(part_count)
+}
+
+/// The media duration of the part, estimated if its real length is not known
+fn part_media_seconds(video: &VideosModel, part_number: usize, part_durations: &[u64]) -> u64 {
+    part_number
+        .checked_sub(1)
+        .and_then(|index| part_durations.get(index))
+        .copied()
+        .unwrap_or_else(|| estimate_part_seconds(video))
+}
+
+/// The longest of the parts that are still to be uploaded.
+///
+/// The real lengths are used when they are known. Otherwise parts are split
+/// at the target duration of the user, only the last one is shorter, so the
+/// longest part is usually the target duration.
+fn longest_part_seconds(
+    video: &VideosModel,
+    user: &UsersModel,
+    part_durations: &[u64],
+    parts: &[usize],
+) -> u64 {
+    if !part_durations.is_empty() {
+        return parts
+            .iter()
+            .filter_map(|part| part_durations.get(part.saturating_sub(1)))
+            .copied()
+            .max()
+            .unwrap_or(0);
+    }
+    let duration = video.duration.max(0) as u64;
+    if video.part_count <= 1 {
+        return duration;
+    }
+    let target = user.youtube_target_duration.max(0) as u64;
+    estimate_part_seconds(video).max(target).min(duration)
+}
+
+/// Gets all part files that still need to be uploaded.
+///
+/// Parts in `uploaded_parts` have already been uploaded (and their files
+/// deleted) by a previous run, so they are not expected in the folder.
+async fn get_part_files(
+    folder_path: &Path,
+    part_count: i32,
+    uploaded_parts: &HashSet<usize>,
+) -> Result<Vec<(PathBuf, usize)>> {
     let mut parts = Vec::new();
+    let count = (part_count as usize).saturating_sub(uploaded_parts.len());
     trace!(
         "getting {} parts from folder '{}'",
         count,
@@ -235,13 +539,18 @@ async fn get_part_files(folder_path: &Path, count: i32) -> Result<Vec<(PathBuf,
         let path = path.path();
         let part_number = get_part_number_from_path(&path)?;
         dbg!(part_number);
+        if uploaded_parts.contains(&part_number) {
+            warn!(
+                "part {} was already uploaded but its file still exists: {}",
+                part_number,
+                path.display()
+            );
+            continue;
+        }
         parts.push((path, part_number));
     }
-    if parts.len() != count as usize {
-        return Err(UploaderError::PartCountMismatch(
-            count as usize,
-            parts.len(),
-        ));
+    if parts.len() != count {
+        return Err(UploaderError::PartCountMismatch(count, parts.len()));
     }
     parts.sort_by_key(|a| a.1);
     Ok(parts)
@@ -272,20 +581,91 @@ fn get_part_number_from_path(path: &Path) -> Result<usize> {
 }
 
 impl UploaderClient {
+    /// Creates the client. The users are authenticated lazily, when the
+    /// first video of them is uploaded.
     pub async fn new(db: DatabaseConnection) -> Result<Self> {
-        let mut clients = HashMap::new();
-
-        let users = twba_local_db::get_watched_users(&db).await?;
-        for user in users {
-            let user_id = user.id.to_string();
-            let client =
-                youtube::YoutubeClient::new(&YOUTUBE_DEFAULT_SCOPES, Some(user.youtube_id)).await?;
-            clients.insert(user_id, client);
-        }
-
         Ok(Self {
             db,
-            youtube_clients: clients,
+            youtube_clients: Arc::new(Mutex::new(HashMap::new())),
+            waiting_for_auth: Arc::new(Mutex::new(HashSet::new())),
+            auth_tasks: Arc::new(Mutex::new(Vec::new())),
         })
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn video(duration: i32, part_count: i32) -> VideosModel {
+        VideosModel {
+            id: 0,
+            status: Status::Split,
+            user_id: 0,
+            name: String::new(),
+            created_at: String::new(),
+            part_count,
+            duration,
+            twitch_id: String::new(),
+            twitch_preview_image_url: None,
+            twitch_download_url: None,
+            youtube_id: None,
+            youtube_playlist_name: String::new(),
+            youtube_preview_image_url: None,
+            youtube_playlist_id: None,
+            youtube_playlist_created_at: None,
+            fail_count: 0,
+            fail_reason: None,
+        }
+    }
+
+    fn user(youtube_target_duration: i32) -> UsersModel {
+        UsersModel {
+            id: 0,
+            twitch_id: String::new(),
+            twitch_name: String::new(),
+            twitch_profile_image_url: None,
+            youtube_id: String::new(),
+            youtube_name: String::new(),
+            youtube_profile_image_url: None,
+            youtube_target_duration,
+            youtube_max_duration: youtube_target_duration * 2,
+            active: true,
+            timezone: "+00:00".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_part_media_seconds() {
+        let durations = [3600, 3600, 300];
+        assert_eq!(300, part_media_seconds(&video(7500, 3), 3, &durations));
+        assert_eq!(3600, part_media_seconds(&video(7500, 3), 1, &durations));
+        assert_eq!(2500, part_media_seconds(&video(7500, 3), 3, &[]));
+    }
+
+    #[test]
+    fn test_longest_part_seconds_estimated() {
+        assert_eq!(
+            600,
+            longest_part_seconds(&video(600, 1), &user(3600), &[], &[1])
+        );
+        assert_eq!(
+            3600,
+            longest_part_seconds(&video(9000, 3), &user(3600), &[], &[1, 2, 3])
+        );
+    }
+
+    #[test]
+    fn test_longest_part_seconds_known() {
+        let durations = [1200, 1300, 500];
+        assert_eq!(
+            1300,
+            longest_part_seconds(&video(3000, 3), &user(3600), &durations, &[1, 2, 3])
+        );
+        // the long parts are already uploaded
+        assert_eq!(
+            500,
+            longest_part_seconds(&video(3000, 3), &user(3600), &durations, &[3])
+        );
+    }
+}
@@ -0,0 +1,134 @@
+use crate::config::BudgetConf;
+use crate::prelude::*;
+use std::fmt::{Display, Formatter};
+use std::time::{Duration, Instant};
+
+/// Keeps track of how much of the configured budget a run has used up.
+#[derive(Debug)]
+pub(crate) struct RunBudget {
+    max_bytes: Option<u64>,
+    max_media_seconds: Option<u64>,
+    deadline: Option<Instant>,
+    used_bytes: u64,
+    used_media_seconds: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum BudgetLimit {
+    Bytes,
+    MediaDuration,
+    Deadline,
+}
+
+impl Display for BudgetLimit {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BudgetLimit::Bytes => write!(f, "byte budget"),
+            BudgetLimit::MediaDuration => write!(f, "media duration budget"),
+            BudgetLimit::Deadline => write!(f, "wall-clock deadline"),
+        }
+    }
+}
+
+impl RunBudget {
+    pub(crate) fn new(conf: &BudgetConf) -> Self {
+        Self::starting_at(conf, Instant::now())
+    }
+    fn starting_at(conf: &BudgetConf, start: Instant) -> Self {
+        Self {
+            max_bytes: conf.max_bytes,
+            max_media_seconds: conf.max_media_seconds,
+            deadline: conf
+                .max_wall_clock_seconds
+                .map(|s| start + Duration::from_secs(s)),
+            used_bytes: 0,
+            used_media_seconds: 0,
+        }
+    }
+
+    /// Records a part that was uploaded (or at least attempted)
+    pub(crate) fn record_part(&mut self, bytes: u64, media_seconds: u64) {
+        self.used_bytes += bytes;
+        self.used_media_seconds += media_seconds;
+        trace!(
+            "used budget: {} bytes, {} media seconds",
+            self.used_bytes,
+            self.used_media_seconds
+        );
+    }
+
+    /// Returns the first limit that has been hit, if any.
+    ///
+    /// This should be checked before starting a new part.
+    pub(crate) fn exhausted(&self) -> Option<BudgetLimit> {
+        self.exhausted_at(Instant::now())
+    }
+    fn exhausted_at(&self, now: Instant) -> Option<BudgetLimit> {
+        if self.max_bytes.is_some_and(|max| self.used_bytes >= max) {
+            return Some(BudgetLimit::Bytes);
+        }
+        if self
+            .max_media_seconds
+            .is_some_and(|max| self.used_media_seconds >= max)
+        {
+            return Some(BudgetLimit::MediaDuration);
+        }
+        if self.deadline.is_some_and(|deadline| now >= deadline) {
+            return Some(BudgetLimit::Deadline);
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_unlimited_budget() {
+        let mut budget = RunBudget::new(&BudgetConf::default());
+        budget.record_part(u64::MAX / 2, u64::MAX / 2);
+        assert_eq!(None, budget.exhausted());
+    }
+
+    #[test]
+    fn test_byte_budget() {
+        let conf = BudgetConf {
+            max_bytes: Some(100),
+            ..Default::default()
+        };
+        let mut budget = RunBudget::new(&conf);
+        budget.record_part(60, 0);
+        assert_eq!(None, budget.exhausted());
+        budget.record_part(40, 0);
+        assert_eq!(Some(BudgetLimit::Bytes), budget.exhausted());
+    }
+
+    #[test]
+    fn test_media_budget() {
+        let conf = BudgetConf {
+            max_media_seconds: Some(3600),
+            ..Default::default()
+        };
+        let mut budget = RunBudget::new(&conf);
+        budget.record_part(0, 3599);
+        assert_eq!(None, budget.exhausted());
+        budget.record_part(0, 1);
+        assert_eq!(Some(BudgetLimit::MediaDuration), budget.exhausted());
+    }
+
+    #[test]
+    fn test_deadline() {
+        let conf = BudgetConf {
+            max_wall_clock_seconds: Some(60),
+            ..Default::default()
+        };
+        let start = Instant::now();
+        let budget = RunBudget::starting_at(&conf, start);
+        assert_eq!(None, budget.exhausted_at(start + Duration::from_secs(59)));
+        assert_eq!(
+            Some(BudgetLimit::Deadline),
+            budget.exhausted_at(start + Duration::from_secs(60))
+        );
+    }
+}
@@ -0,0 +1,531 @@
+use super::youtube::{
+    remove_cached_token, scopes_for_user, ClientState, LongUploadsStatus, YoutubeClient,
+    DEFAULT_CATEGORY_REGION, LONG_UPLOADS_RECHECK,
+};
+use super::{quota_cost, UploaderClient};
+use crate::config::YoutubeFeature;
+use crate::errors::AuthError;
+use crate::notification::send_notification;
+use crate::prelude::*;
+use crate::{store, UPLOADER_CONF};
+use chrono::{DateTime, Duration, Utc};
+use google_youtube3::oauth2;
+use std::sync::Arc;
+use tracing::instrument;
+use twba_local_db::prelude::*;
+use twba_local_db::re_exports::sea_orm::{
+    ActiveModelTrait, ActiveValue, ColumnTrait, EntityTrait, IntoActiveModel, PaginatorTrait,
+    QueryFilter,
+};
+
+impl UploaderClient {
+    /// Gets the client for the user of the video, creating it if needed.
+    ///
+    /// Returns `None` if the user has to authenticate first. In that case
+    /// the authentication keeps running in the background and the client is
+    /// available once the user authenticated.
+    #[instrument(skip(self, user), fields(user_id=user.id))]
+    pub(super) async fn get_client_for_user(
+        &self,
+        user: &UsersModel,
+    ) -> Result<Option<Arc<YoutubeClient>>> {
+        if let Some(client) = self.cached_client(user.id) {
+            return Ok(Some(client));
+        }
+        if self.is_waiting_for_auth(user.id) {
+            trace!("user {} is still authenticating", user.id);
+            return Ok(None);
+        }
+
+        let state = YoutubeClient::new(
+            &self.db,
+            &scopes_for_user(&user.youtube_id),
+            Some(user.youtube_id.clone()),
+        )
+        .await;
+        match state {
+            Ok(ClientState::Ready(client)) => match self.accept_client(user, client).await {
+                Ok(client) => Ok(Some(client)),
+                Err(e) => {
+                    self.reject_client(user, &e).await?;
+                    Ok(None)
+                }
+            },
+            Ok(ClientState::WaitingForUser(handle)) => {
+                warn!(
+                    "user {} has to authenticate, skipping their videos",
+                    user.id
+                );
+                store::mark_needs_reauth(&self.db, user.id, "interactive authentication needed")
+                    .await?;
+                self.set_waiting_for_auth(user.id, true);
+                let this = self.clone();
+                let user = user.clone();
+                let task = tokio::spawn(async move {
+                    let accepted = match handle.await {
+                        Ok(Ok(client)) => this.accept_client(&user, client).await.map(|_| ()),
+                        Ok(Err(e)) => Err(e),
+                        Err(e) => Err(AuthError::AuthTask(e).into()),
+                    };
+                    match accepted {
+                        Ok(()) => info!("user {} authenticated", user.id),
+                        Err(e) => {
+                            if let Err(e) = this.reject_client(&user, &e).await {
+                                error!("could not mark user {} for re-auth: {}", user.id, e);
+                            }
+                        }
+                    }
+                    this.set_waiting_for_auth(user.id, false);
+                });
+                {
+                    let mut auth_tasks = self.auth_tasks.lock().expect("auth task lock poisoned");
+                    auth_tasks.retain(|task| !task.is_finished());
+                    auth_tasks.push(task);
+                }
+                Ok(None)
+            }
+            Err(e) => {
+                error!("could not create client for user {}: {}", user.id, e);
+                if requires_reauth(&e) {
+                    store::mark_needs_reauth(&self.db, user.id, e.to_string()).await?;
+                }
+                Ok(None)
+            }
+        }
+    }
+
+    /// Checks that the client is authenticated for the channel of the user
+    /// and caches it.
+    ///
+    /// The channel title is stored as the youtube name of the user.
+    async fn accept_client(
+        &self,
+        user: &UsersModel,
+        mut client: YoutubeClient,
+    ) -> Result<Arc<YoutubeClient>> {
+        let channel = client.get_own_channel().await;
+        self.record_quota(user.id, quota_cost::LIST).await;
+        let channel = channel?;
+        if channel.id != user.youtube_id {
+            return Err(AuthError::ChannelMismatch {
+                expected: user.youtube_id.clone(),
+                actual: channel.id,
+            }
+            .into());
+        }
+        if let Some(title) = channel.title.filter(|title| *title != user.youtube_name) {
+            info!("updating youtube name of user {} to {}", user.id, title);
+            let mut user = user.clone().into_active_model();
+            user.youtube_name = ActiveValue::Set(title);
+            user.update(&self.db).await?;
+        }
+        if !channel.long_uploads.allows_long_uploads() {
+            warn!(
+                "channel of user {} can not upload long videos: {}",
+                user.id, channel.long_uploads
+            );
+            send_notification(format!(
+                "The channel of {} ({}) can not upload videos longer than 15 minutes \
+                (long uploads: {}), so longer parts are not uploaded.\n\
+                Verify the channel with a phone number at https://www.youtube.com/verify, \
+                it is checked again within an hour.",
+                user.twitch_name, user.youtube_id, channel.long_uploads
+            ))
+            .await;
+        }
+        client.set_long_uploads(channel.long_uploads);
+        client.set_category_region(
+            channel
+                .country
+                .unwrap_or_else(|| DEFAULT_CATEGORY_REGION.to_string()),
+        );
+        self.check_categories(user, &client).await;
+        store::clear_needs_reauth(&self.db, user.id).await?;
+        Ok(self.cache_client(user.id, client))
+    }
+
+    /// Gets the long upload status of the channel, reading it again if the
+    /// cached one is older than [`LONG_UPLOADS_RECHECK`].
+    ///
+    /// If reading it fails, the cached status is used.
+    pub(super) async fn refresh_long_uploads(
+        &self,
+        user: &UsersModel,
+        client: &YoutubeClient,
+    ) -> LongUploadsStatus {
+        if client.long_uploads_age() < LONG_UPLOADS_RECHECK {
+            return client.long_uploads();
+        }
+        let channel = client.get_own_channel().await;
+        self.record_quota(user.id, quota_cost::LIST).await;
+        match channel {
+            Ok(channel) => {
+                if channel.long_uploads != client.long_uploads() {
+                    info!(
+                        "long uploads of user {} changed from {} to {}",
+                        user.id,
+                        client.long_uploads(),
+                        channel.long_uploads
+                    );
+                }
+                client.set_long_uploads(channel.long_uploads);
+            }
+            Err(e) => {
+                warn!("could not read the channel of user {}: {}", user.id, e);
+                // do not read it again for every video while the api fails
+                client.set_long_uploads(client.long_uploads());
+            }
+        }
+        client.long_uploads()
+    }
+
+    /// Checks the configured categories of the user against the ones YouTube
+    /// allows in the region of the channel, so an invalid id is noticed before
+    /// uploading.
+    ///
+    /// Nothing happens if the client was already checked. If the check fails,
+    /// it is tried again the next time this is called.
+    pub(super) async fn check_categories(&self, user: &UsersModel, client: &YoutubeClient) {
+        if client.categories_checked() {
+            return;
+        }
+        if let Some(invalid) = self
+            .find_invalid_categories(user, client, client.category_region())
+            .await
+        {
+            client.set_invalid_categories(invalid);
+        }
+    }
+
+    /// Finds the configured categories of the user that can not be used in
+    /// the region. Returns `None` if the allowed categories could not be read.
+    async fn find_invalid_categories(
+        &self,
+        user: &UsersModel,
+        client: &YoutubeClient,
+        region: &str,
+    ) -> Option<Vec<u32>> {
+        let assignable = client.get_assignable_categories(region).await;
+        self.record_quota(user.id, quota_cost::LIST).await;
+        let assignable = match assignable {
+            Ok(assignable) => assignable,
+            Err(e) => {
+                warn!(
+                    "could not check the categories of user {}, trying again later: {}",
+                    user.id, e
+                );
+                return None;
+            }
+        };
+        let invalid: Vec<u32> = UPLOADER_CONF
+            .metadata_for(user.id)
+            .category_ids()
+            .into_iter()
+            .filter(|category| !assignable.contains(category))
+            .collect();
+        if !invalid.is_empty() {
+            let invalid_list = invalid
+                .iter()
+                .map(u32::to_string)
+                .collect::<Vec<_>>()
+                .join(", ");
+            error!(
+                "categories {} of user {} can not be used in region {}",
+                invalid_list, user.id, region
+            );
+            send_notification(format!(
+                "The categories {} configured for {} ({}) can not be used in region {}. \
+                Videos with them are not uploaded until the config is fixed.",
+                invalid_list, user.twitch_name, user.youtube_id, region
+            ))
+            .await;
+        }
+        Some(invalid)
+    }
+
+    /// Marks the user for re-auth after the authentication did not work out.
+    ///
+    /// A token for the wrong channel is removed, so it is not used again.
+    /// Transient errors, like a failed channel lookup, are only logged and
+    /// the client is created again on the next attempt.
+    async fn reject_client(&self, user: &UsersModel, e: &UploaderError) -> Result<()> {
+        error!("authentication of user {} failed: {}", user.id, e);
+        if !requires_reauth(e) {
+            return Ok(());
+        }
+        if matches!(
+            e,
+            UploaderError::AuthError(AuthError::ChannelMismatch { .. })
+        ) {
+            remove_cached_token(&self.db, &user.youtube_id).await?;
+            send_notification(format!(
+                "Authentication for {} used the wrong account: {}",
+                user.twitch_name, e
+            ))
+            .await;
+        }
+        store::mark_needs_reauth(&self.db, user.id, e.to_string()).await
+    }
+
+    /// Creates the clients of all active users.
+    ///
+    /// This checks their channel, long uploads and categories before the
+    /// first upload, so problems are reported right away. Users that have to
+    /// authenticate start doing so in the background.
+    pub(crate) async fn prepare_clients(&self) -> Result<()> {
+        let users = Users::find()
+            .filter(UsersColumn::Active.eq(true))
+            .all(&self.db)
+            .await?;
+        for user in users {
+            if let Err(e) = self.get_client_for_user(&user).await {
+                error!("could not prepare the client of user {}: {}", user.id, e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Gets the client for the user and waits for the authentication if needed.
+    ///
+    /// This is meant for commands that run for a single user.
+    pub(crate) async fn get_client_for_user_blocking(
+        &self,
+        user: &UsersModel,
+    ) -> Result<Arc<YoutubeClient>> {
+        if let Some(client) = self.cached_client(user.id) {
+            return Ok(client);
+        }
+        let client = YoutubeClient::new(
+            &self.db,
+            &scopes_for_user(&user.youtube_id),
+            Some(user.youtube_id.clone()),
+        )
+        .await?
+        .wait()
+        .await?;
+        match self.accept_client(user, client).await {
+            Ok(client) => Ok(client),
+            Err(e) => {
+                self.reject_client(user, &e).await?;
+                Err(e)
+            }
+        }
+    }
+
+    /// Starts a new authentication for the user with the channel id.
+    ///
+    /// The cached client is dropped, so a revoked token is noticed and the
+    /// flow starts in the background. Nothing happens if the cached token
+    /// still works.
+    #[instrument(skip(self))]
+    pub(crate) async fn start_reauth(&self, youtube_id: &str) -> Result<()> {
+        let user = Users::find()
+            .filter(UsersColumn::YoutubeId.eq(youtube_id))
+            .one(&self.db)
+            .await?
+            .ok_or_else(|| UploaderError::UnknownChannel(youtube_id.to_string()))?;
+        if self.is_waiting_for_auth(user.id) {
+            info!("user {} is already authenticating", user.id);
+            return Ok(());
+        }
+        self.youtube_clients
+            .lock()
+            .expect("client lock poisoned")
+            .remove(&user.id);
+        if self.get_client_for_user(&user).await?.is_some() {
+            info!(
+                "the token of user {} still works, no need to authenticate",
+                user.id
+            );
+        }
+        Ok(())
+    }
+
+    /// Waits for the authentications that are running in the background.
+    ///
+    /// Returns whether there were any, so the caller knows if another
+    /// attempt could upload more videos.
+    pub(crate) async fn wait_for_pending_auth(&self) -> bool {
+        let tasks: Vec<_> = self
+            .auth_tasks
+            .lock()
+            .expect("auth task lock poisoned")
+            .drain(..)
+            .collect();
+        if tasks.is_empty() {
+            return false;
+        }
+        info!("waiting for {} authentications to finish", tasks.len());
+        for task in tasks {
+            if let Err(e) = task.await {
+                error!("authentication task failed: {}", e);
+            }
+        }
+        true
+    }
+
+    fn cached_client(&self, user_id: i32) -> Option<Arc<YoutubeClient>> {
+        self.youtube_clients
+            .lock()
+            .expect("client lock poisoned")
+            .get(&user_id)
+            .cloned()
+    }
+    fn cache_client(&self, user_id: i32, client: YoutubeClient) -> Arc<YoutubeClient> {
+        let client = Arc::new(client);
+        self.youtube_clients
+            .lock()
+            .expect("client lock poisoned")
+            .insert(user_id, client.clone());
+        client
+    }
+    fn is_waiting_for_auth(&self, user_id: i32) -> bool {
+        self.waiting_for_auth
+            .lock()
+            .expect("auth lock poisoned")
+            .contains(&user_id)
+    }
+    fn set_waiting_for_auth(&self, user_id: i32, waiting: bool) {
+        let mut waiting_for_auth = self.waiting_for_auth.lock().expect("auth lock poisoned");
+        if waiting {
+            waiting_for_auth.insert(user_id);
+        } else {
+            waiting_for_auth.remove(&user_id);
+        }
+    }
+
+    /// Asks the user of the video to consent to the missing feature.
+    ///
+    /// The token and client of the user are dropped, so the next attempt
+    /// starts a new authentication with all configured scopes. The video is
+    /// reset, so it is resumed once the user consented.
+    #[instrument(skip(self, video), fields(id=video.id))]
+    pub(super) async fn request_reconsent(
+        &self,
+        video: &VideosModel,
+        feature: YoutubeFeature,
+    ) -> Result<()> {
+        let user = self.get_user_for_video(video).await?;
+        self.youtube_clients
+            .lock()
+            .expect("client lock poisoned")
+            .remove(&user.id);
+        remove_cached_token(&self.db, &user.youtube_id).await?;
+        self.reset_for_resume(video).await?;
+
+        let already_marked = store::get_auth_state(&self.db, user.id)
+            .await?
+            .is_some_and(|state| state.needs_reauth);
+        store::mark_needs_reauth(&self.db, user.id, format!("missing access for {}", feature))
+            .await?;
+        if !already_marked {
+            send_notification(format!(
+                "{} ({}) did not grant access for {}. Please authenticate again and allow it.",
+                user.twitch_name, user.youtube_id, feature
+            ))
+            .await;
+        }
+        Ok(())
+    }
+
+    /// Reminds the operator that the user still has to authenticate.
+    ///
+    /// The time between reminders grows with every reminder, following
+    /// `reminder_intervals_minutes` and staying at the last interval.
+    #[instrument(skip(self, user), fields(user_id=user.id))]
+    pub(super) async fn remind_reauth(&self, user: &UsersModel) -> Result<()> {
+        let Some(state) = store::get_auth_state(&self.db, user.id).await? else {
+            return Ok(());
+        };
+        if !state.needs_reauth {
+            return Ok(());
+        }
+        let intervals = &UPLOADER_CONF.auth.reminder_intervals_minutes;
+        let Some(interval) = intervals
+            .get(state.reminder_count as usize)
+            .or(intervals.last())
+        else {
+            return Ok(());
+        };
+        let last = state
+            .last_reminder_at
+            .as_ref()
+            .or(state.requested_at.as_ref())
+            .and_then(|date| DateTime::parse_from_rfc3339(date).ok())
+            .map(|date| date.to_utc());
+        if last.is_some_and(|last| Utc::now() < last + Duration::minutes(*interval as i64)) {
+            trace!("not reminding about user {} yet", user.id);
+            return Ok(());
+        }
+
+        let waiting_videos = Videos::find()
+            .filter(VideosColumn::UserId.eq(user.id))
+            .filter(VideosColumn::Status.is_in([Status::Split, Status::PartiallyUploaded]))
+            .count(&self.db)
+            .await?;
+        send_notification(format!(
+            "Reminder {}: {} ({}) still has to authenticate again, {} videos are waiting.\nReason: {}",
+            state.reminder_count + 1,
+            user.twitch_name,
+            user.youtube_id,
+            waiting_videos,
+            state.reason.as_deref().unwrap_or("unknown")
+        ))
+        .await;
+        store::record_reauth_reminder(&self.db, state).await
+    }
+}
+
+/// Whether the error means the user has to authenticate again.
+///
+/// Only a revoked or missing token and the wrong account count, everything
+/// else (network, files, database) might work on the next attempt.
+fn requires_reauth(e: &UploaderError) -> bool {
+    match e {
+        UploaderError::AuthError(AuthError::GetAccessToken(e)) => matches!(
+            e.downcast_ref::<oauth2::Error>(),
+            Some(oauth2::Error::AuthError(_) | oauth2::Error::MissingAccessToken)
+        ),
+        UploaderError::AuthError(
+            AuthError::RefreshRejected(_)
+            | AuthError::NoChannel
+            | AuthError::ChannelMismatch { .. },
+        ) => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_requires_reauth() {
+        assert!(requires_reauth(&AuthError::NoChannel.into()));
+        assert!(requires_reauth(
+            &AuthError::ChannelMismatch {
+                expected: "a".to_string(),
+                actual: "b".to_string(),
+            }
+            .into()
+        ));
+        assert!(requires_reauth(
+            &AuthError::RefreshRejected("400: invalid_grant".to_string()).into()
+        ));
+        assert!(requires_reauth(
+            &AuthError::GetAccessToken(oauth2::Error::MissingAccessToken.into()).into()
+        ));
+
+        let io_error = || std::io::Error::new(std::io::ErrorKind::Other, "offline");
+        assert!(!requires_reauth(
+            &AuthError::GetAccessToken(oauth2::Error::LowLevelError(io_error()).into()).into()
+        ));
+        assert!(!requires_reauth(
+            &AuthError::ReadApplicationSecret(io_error()).into()
+        ));
+        assert!(!requires_reauth(&AuthError::CreateAuth(io_error()).into()));
+        assert!(!requires_reauth(&UploaderError::YoutubeError(
+            google_youtube3::Error::Cancelled
+        )));
+    }
+}
@@ -1,16 +1,26 @@
 use crate::client::data::substitutions::*;
+use crate::config::{Privacy, TemplatesConf};
+use crate::overrides::VideoOverrides;
 use crate::prelude::*;
-use crate::CONF;
+use crate::{CONF, UPLOADER_CONF};
 use chrono::{DateTime, Datelike, ParseResult, Utc};
 use google_youtube3::api::enums::{PlaylistStatusPrivacyStatusEnum, VideoStatusPrivacyStatusEnum};
 use std::fmt::Debug;
-use std::str::FromStr;
+use stream_metadata::StreamMetadata;
+use template::TemplateContext;
 use twba_local_db::prelude::{UsersModel, VideosModel};
 
+mod chapters;
+pub(crate) mod stream_metadata;
+mod tags;
+mod template;
+
 /// The maximum length of a YouTube title that is allowed
 ///
 /// This is a constant because it is a hard limit set by YouTube
 const YOUTUBE_TITLE_MAX_LENGTH: usize = 100;
+/// The maximum length of a YouTube description, in bytes like YouTube counts it
+const YOUTUBE_DESCRIPTION_MAX_LENGTH: usize = 5000;
 pub mod substitutions {
     pub const ORIGINAL_TITLE: &str = "$$original_title$$";
     pub const ORIGINAL_DESCRIPTION: &str = "$$original_description$$";
@@ -36,6 +46,7 @@ pub struct VideoData {
     pub video_tags: Vec<String>,
     pub video_category: u32,
     pub video_privacy: VideoStatusPrivacyStatusEnum,
+    pub made_for_kids: bool,
     pub playlist_title: String,
     pub playlist_description: String,
     pub playlist_privacy: PlaylistStatusPrivacyStatusEnum,
@@ -63,22 +74,106 @@ impl Default for Templates {
     }
 }
 
+/// Creates the description, from the override of the video if it has one
 pub(crate) fn create_youtube_description(
     video: &VideosModel,
     user: &UsersModel,
     target: Location,
+    overrides: &VideoOverrides,
+    part_durations: &[u64],
+    stream: &StreamMetadata,
 ) -> Result<String> {
-    let s = get_description_template(target);
-    let description = substitute(s, video, user, target)?;
-    Ok(description)
+    let s = overrides
+        .description
+        .clone()
+        .unwrap_or_else(|| get_description_template(target, user.id));
+    let context = TemplateContext::new(video, user, target, part_durations, stream)?;
+    let description = template::render(&s, &context)?;
+    let metadata = UPLOADER_CONF.metadata_for(user.id);
+    let used = template::referenced_variables(&s)?;
+    let appended = [
+        ("chapters", metadata.append_chapters, &context.chapters),
+        ("hashtags", metadata.hashtags, &context.hashtags),
+    ];
+    let blocks: Vec<&str> = appended
+        .into_iter()
+        .filter(|(name, enabled, _)| *enabled && !used.contains(*name))
+        .map(|(_, _, block)| block.as_str())
+        .collect();
+    Ok(fit_description(description, &blocks))
+}
+
+/// Appends the blocks that still fit and cuts the description to the length
+/// YouTube allows.
+///
+/// The blocks are optional, so one that does not fit is left out instead of
+/// cutting the description.
+fn fit_description(mut description: String, blocks: &[&str]) -> String {
+    for block in blocks.iter().filter(|block| !block.is_empty()) {
+        if description.len() + 2 + block.len() > YOUTUBE_DESCRIPTION_MAX_LENGTH {
+            warn!("leaving out a block that does not fit into the description");
+            continue;
+        }
+        description.push_str("\n\n");
+        description.push_str(block);
+    }
+    if description.len() > YOUTUBE_DESCRIPTION_MAX_LENGTH {
+        warn!(
+            "description is too long ({} bytes), cutting it",
+            description.len()
+        );
+        const SHORTEN_CHARS: &str = "...";
+        let mut end = YOUTUBE_DESCRIPTION_MAX_LENGTH - SHORTEN_CHARS.len();
+        while !description.is_char_boundary(end) {
+            end -= 1;
+        }
+        description.truncate(end);
+        description.push_str(SHORTEN_CHARS);
+    }
+    description
 }
+/// Creates the tags of the parts.
+///
+/// The override replaces the configured tags, the channel name and the game
+/// are added to either if enabled.
+pub(crate) fn create_youtube_tags(
+    user: &UsersModel,
+    overrides: &VideoOverrides,
+    stream: &StreamMetadata,
+) -> Vec<String> {
+    let metadata = UPLOADER_CONF.metadata_for(user.id);
+    let mut all_tags = overrides.tags.clone().unwrap_or(metadata.tags);
+    if metadata.tag_channel_name {
+        all_tags.push(user.twitch_name.clone());
+    }
+    if metadata.tag_game {
+        all_tags.extend(stream.game.clone());
+    }
+    tags::build_tags(all_tags)
+}
+
+/// Creates the title, from the override of the video if it has one.
+///
+/// The playlist prefers the playlist name over the title override.
 pub(crate) fn create_youtube_title(
     video: &VideosModel,
     user: &UsersModel,
     target: Location,
+    overrides: &VideoOverrides,
+    part_durations: &[u64],
+    stream: &StreamMetadata,
 ) -> Result<String> {
-    let title_template = get_title_template(target);
-    let title = substitute(title_template, video, user, target)?;
+    let title_override = match target {
+        Location::Video(_) => overrides.title.clone(),
+        Location::Playlist => overrides
+            .playlist_name
+            .clone()
+            .or_else(|| overrides.title.clone()),
+    };
+    let title_template = title_override.unwrap_or_else(|| get_title_template(target, user.id));
+    let title = substitute(title_template, video, user, target, part_durations, stream)?;
+    // placeholders that are empty for the playlist, like the part, leave spaces behind
+    let title = title.trim().to_string();
     let max_len = match target {
         Location::Video(_) => Some(YOUTUBE_TITLE_MAX_LENGTH),
         Location::Playlist => Some(YOUTUBE_TITLE_MAX_LENGTH),
@@ -87,19 +182,55 @@ pub(crate) fn create_youtube_title(
     Ok(title)
 }
 
-fn get_title_template(target: Location) -> String {
-    let templates = Templates::default();
+impl Templates {
+    /// The configured templates, with the defaults for the ones that are not set.
+    ///
+    /// The first of `confs` that sets a template wins.
+    fn from_conf(confs: &[&TemplatesConf], default_description: &str) -> Self {
+        let defaults = Templates::default();
+        let title = |get: fn(&TemplatesConf) -> &Option<String>, default: String| {
+            confs
+                .iter()
+                .find_map(|conf| get(conf).clone())
+                .unwrap_or(default)
+        };
+        let description = |get: fn(&TemplatesConf) -> &Option<String>, default: String| {
+            confs
+                .iter()
+                .find_map(|conf| get(conf).clone())
+                .or_else(|| Some(default_description.to_string()).filter(|d| !d.is_empty()))
+                .unwrap_or(default)
+        };
+        Self {
+            video_title: title(|c| &c.video_title, defaults.video_title),
+            video_description: description(|c| &c.video_description, defaults.video_description),
+            playlist_title: title(|c| &c.playlist_title, defaults.playlist_title),
+            playlist_description: description(
+                |c| &c.playlist_description,
+                defaults.playlist_description,
+            ),
+        }
+    }
+    /// The templates for the user, with their profile applied
+    fn configured(user_id: i32) -> Self {
+        let mut confs = Vec::new();
+        if let Some(profile) = UPLOADER_CONF.profiles.get(&user_id) {
+            confs.push(&profile.templates);
+        }
+        confs.push(&UPLOADER_CONF.templates);
+        Self::from_conf(&confs, &CONF.google.youtube.default_description_template)
+    }
+}
+
+fn get_title_template(target: Location, user_id: i32) -> String {
+    let templates = Templates::configured(user_id);
     match target {
         Location::Video(_) => templates.video_title,
         Location::Playlist => templates.playlist_title,
     }
 }
-fn get_description_template(target: Location) -> String {
-    let configured = &CONF.google.youtube.default_description_template;
-    if !configured.is_empty() {
-        return configured.to_string();
-    }
-    let templates = Templates::default();
+fn get_description_template(target: Location, user_id: i32) -> String {
+    let templates = Templates::configured(user_id);
     match target {
         Location::Video(_) => templates.video_description,
         Location::Playlist => templates.playlist_description,
@@ -111,50 +242,26 @@ fn substitute(
     video: &VideosModel,
     user: &UsersModel,
     target: Location,
+    part_durations: &[u64],
+    stream: &StreamMetadata,
 ) -> Result<String> {
-    let max = video.part_count as usize;
-    let s = substitute_common(input, video, user, max)?;
-
-    let title = match target {
-        Location::Video(current) => substitute_part_ident(&s, current, max),
-        _ => s,
-    };
-    Ok(title)
+    let context = TemplateContext::new(video, user, target, part_durations, stream)?;
+    template::render(&input, &context)
 }
-fn substitute_part_ident(input: &str, current: usize, max: usize) -> String {
-    let part_prefix = if max > 1 {
-        format_progress(max, current)
-    } else {
-        String::new()
-    };
-    input.replace(PART_IDENT, &part_prefix)
+
+pub(crate) fn video_privacy(privacy: Privacy) -> VideoStatusPrivacyStatusEnum {
+    match privacy {
+        Privacy::Private => VideoStatusPrivacyStatusEnum::Private,
+        Privacy::Unlisted => VideoStatusPrivacyStatusEnum::Unlisted,
+        Privacy::Public => VideoStatusPrivacyStatusEnum::Public,
+    }
 }
-fn substitute_common(
-    input: String,
-    video: &VideosModel,
-    user: &UsersModel,
-    max: usize,
-) -> Result<String> {
-    let date = parse_date(&video.created_at).map_err(UploaderError::ParseDate)?;
-    let timezone =
-        chrono::FixedOffset::from_str(&user.timezone).map_err(UploaderError::ParseDate)?;
-    let date = date.with_timezone(&timezone);
-    let date_prefix = get_date_prefix(date.date_naive());
-    Ok(input
-        .replace(ORIGINAL_TITLE, &video.name)
-        .replace(ORIGINAL_DESCRIPTION, "")
-        .replace(UPLOAD_DATE, &date.to_string())
-        .replace(UPLOAD_DATE_SHORT, &date_prefix)
-        .replace(
-            TWITCH_URL,
-            video.twitch_download_url.as_ref().unwrap_or(&String::new()),
-        )
-        .replace(TWITCH_CHANNEL_NAME, &user.twitch_name)
-        .replace(
-            TWITCH_CHANNEL_URL,
-            &format!("https://twitch.tv/{}", &user.twitch_id),
-        )
-        .replace(PART_COUNT, &max.to_string()))
+pub(crate) fn playlist_privacy(privacy: Privacy) -> PlaylistStatusPrivacyStatusEnum {
+    match privacy {
+        Privacy::Private => PlaylistStatusPrivacyStatusEnum::Private,
+        Privacy::Unlisted => PlaylistStatusPrivacyStatusEnum::Unlisted,
+        Privacy::Public => PlaylistStatusPrivacyStatusEnum::Public,
+    }
 }
 
 fn shorten_string_if_needed(s: impl Into<String>, target_len: Option<usize>) -> String {
@@ -185,6 +292,50 @@ fn get_date_prefix(date: chrono::NaiveDate) -> String {
     )
 }
 
+/// Formats seconds like `1:02:03`, or `2:03` if it is shorter than an hour
+pub(crate) fn format_duration(seconds: u64) -> String {
+    let (hours, minutes, seconds) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+    if hours > 0 {
+        format!("{}:{:02}:{:02}", hours, minutes, seconds)
+    } else {
+        format!("{}:{:02}", minutes, seconds)
+    }
+}
+
+/// Gets where the part starts and ends in the stream, in seconds.
+///
+/// Uses the real part lengths if all of them are known. Otherwise the parts
+/// are estimated: streams are split at the target duration of the user, so
+/// every part but the last one has that length.
+pub(crate) fn part_range_seconds(
+    video: &VideosModel,
+    user: &UsersModel,
+    part: usize,
+    part_durations: &[u64],
+) -> (u64, u64) {
+    let part_count = video.part_count.max(1) as usize;
+    let part = part.clamp(1, part_count);
+    if part_durations.len() == part_count {
+        let start = part_durations[..part - 1].iter().sum::<u64>();
+        return (start, start + part_durations[part - 1]);
+    }
+
+    let duration = video.duration.max(0) as u64;
+    let target = user.youtube_target_duration.max(0) as u64;
+    let part_length = if target == 0 || target * (part_count as u64 - 1) >= duration {
+        duration.div_ceil(part_count as u64)
+    } else {
+        target
+    };
+    let start = (part_length * (part as u64 - 1)).min(duration);
+    let end = if part == part_count {
+        duration
+    } else {
+        (start + part_length).min(duration)
+    };
+    (start, end)
+}
+
 fn format_progress(max: usize, current: usize) -> String {
     let width = (max.checked_ilog10().unwrap_or(0) + 1) as usize;
     format!("[{:0width$}/{:0width$}]", current, max, width = width)
@@ -197,7 +348,11 @@ fn parse_date(date: &str) -> ParseResult<DateTime<Utc>> {
 #[cfg(test)]
 mod test {
     use crate::client::data::create_youtube_title;
+    use crate::client::data::stream_metadata::StreamMetadata;
     use crate::client::data::Location;
+    use crate::client::data::Templates;
+    use crate::config::TemplatesConf;
+    use crate::overrides::VideoOverrides;
     use crate::prelude::twba_local_db::prelude::{Status, UsersModel, VideosModel};
 
     #[test]
@@ -219,38 +374,86 @@ mod test {
     #[test]
     fn test_create_youtube_title_playlist() {
         let (x, user) = get_test_sample_data();
-        let playlist = create_youtube_title(&x, &user, Location::Playlist).unwrap();
+        let playlist = create_youtube_title(
+            &x,
+            &user,
+            Location::Playlist,
+            &VideoOverrides::default(),
+            &[],
+            &StreamMetadata::default(),
+        )
+        .unwrap();
         assert_eq!("[2023-10-09] wow", playlist);
     }
     #[test]
     fn test_create_youtube_title_playlist_with_timezone() {
         let (x, mut user) = get_test_sample_data();
         user.timezone = "-07:00".to_string(); //streamers timezone is -07:00 (PDT)
-        let playlist = create_youtube_title(&x, &user, Location::Playlist).unwrap();
+        let playlist = create_youtube_title(
+            &x,
+            &user,
+            Location::Playlist,
+            &VideoOverrides::default(),
+            &[],
+            &StreamMetadata::default(),
+        )
+        .unwrap();
         assert_eq!("[2023-10-08] wow", playlist);
     }
     #[test]
     fn test_create_youtube_title_video_1() {
         let (x, user) = get_test_sample_data();
-        let video = create_youtube_title(&x, &user, Location::Video(1)).unwrap();
+        let video = create_youtube_title(
+            &x,
+            &user,
+            Location::Video(1),
+            &VideoOverrides::default(),
+            &[],
+            &StreamMetadata::default(),
+        )
+        .unwrap();
         assert_eq!("[2023-10-09][1/4] wow", video);
     }
     #[test]
     fn test_create_youtube_title_video_2() {
         let (x, user) = get_test_sample_data();
-        let video = create_youtube_title(&x, &user, Location::Video(2)).unwrap();
+        let video = create_youtube_title(
+            &x,
+            &user,
+            Location::Video(2),
+            &VideoOverrides::default(),
+            &[],
+            &StreamMetadata::default(),
+        )
+        .unwrap();
         assert_eq!("[2023-10-09][2/4] wow", video);
     }
     #[test]
     fn test_create_youtube_title_video_3() {
         let (x, user) = get_test_sample_data();
-        let video = create_youtube_title(&x, &user, Location::Video(3)).unwrap();
+        let video = create_youtube_title(
+            &x,
+            &user,
+            Location::Video(3),
+            &VideoOverrides::default(),
+            &[],
+            &StreamMetadata::default(),
+        )
+        .unwrap();
         assert_eq!("[2023-10-09][3/4] wow", video);
     }
     #[test]
     fn test_create_youtube_title_video_4() {
         let (x, user) = get_test_sample_data();
-        let video = create_youtube_title(&x, &user, Location::Video(4)).unwrap();
+        let video = create_youtube_title(
+            &x,
+            &user,
+            Location::Video(4),
+            &VideoOverrides::default(),
+            &[],
+            &StreamMetadata::default(),
+        )
+        .unwrap();
         assert_eq!("[2023-10-09][4/4] wow", video);
     }
     #[test]
@@ -258,10 +461,222 @@ mod test {
         let (mut x, user) = get_test_sample_data();
 
         x.part_count = 14;
-        let video = create_youtube_title(&x, &user, Location::Video(2)).unwrap();
+        let video = create_youtube_title(
+            &x,
+            &user,
+            Location::Video(2),
+            &VideoOverrides::default(),
+            &[],
+            &StreamMetadata::default(),
+        )
+        .unwrap();
         assert_eq!("[2023-10-09][02/14] wow", video);
     }
 
+    #[test]
+    fn test_templates_from_conf() {
+        let conf = TemplatesConf {
+            video_title: Some("video title".to_string()),
+            playlist_description: Some("playlist description".to_string()),
+            ..TemplatesConf::default()
+        };
+        let templates = Templates::from_conf(&[&conf], "");
+        let defaults = Templates::default();
+        assert_eq!("video title", templates.video_title);
+        assert_eq!(defaults.video_description, templates.video_description);
+        assert_eq!(defaults.playlist_title, templates.playlist_title);
+        assert_eq!("playlist description", templates.playlist_description);
+
+        let templates = Templates::from_conf(&[&conf], "old description");
+        assert_eq!("old description", templates.video_description);
+        assert_eq!("playlist description", templates.playlist_description);
+    }
+    #[test]
+    fn test_templates_from_profile() {
+        let profile = TemplatesConf {
+            video_title: Some("profile title".to_string()),
+            ..TemplatesConf::default()
+        };
+        let conf = TemplatesConf {
+            video_title: Some("video title".to_string()),
+            playlist_title: Some("playlist title".to_string()),
+            ..TemplatesConf::default()
+        };
+        let templates = Templates::from_conf(&[&profile, &conf], "");
+        assert_eq!("profile title", templates.video_title);
+        assert_eq!("playlist title", templates.playlist_title);
+    }
+
+    #[test]
+    fn test_substitute_with_template_syntax() {
+        let (x, user) = get_test_sample_data();
+        let template =
+            "{% if part_count > 1 %}Part {{ part }}: {% endif %}{{ original_title | upper }} \
+            {{ upload_datetime | date('%d.%m.%Y') }}"
+                .to_string();
+        let title = super::substitute(
+            template,
+            &x,
+            &user,
+            Location::Video(2),
+            &[],
+            &StreamMetadata::default(),
+        )
+        .unwrap();
+        assert_eq!("Part 2: WOW 09.10.2023", title);
+    }
+    #[test]
+    fn test_substitute_unknown_placeholder() {
+        let (x, user) = get_test_sample_data();
+        let result = super::substitute(
+            "$$orginal_title$$".to_string(),
+            &x,
+            &user,
+            Location::Playlist,
+            &[],
+            &StreamMetadata::default(),
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_create_youtube_title_with_overrides() {
+        let (x, user) = get_test_sample_data();
+        let overrides = VideoOverrides {
+            title: Some("Special $$part_ident$$".to_string()),
+            ..VideoOverrides::default()
+        };
+        let video = create_youtube_title(
+            &x,
+            &user,
+            Location::Video(2),
+            &overrides,
+            &[],
+            &StreamMetadata::default(),
+        )
+        .unwrap();
+        assert_eq!("Special [2/4]", video);
+        let playlist = create_youtube_title(
+            &x,
+            &user,
+            Location::Playlist,
+            &overrides,
+            &[],
+            &StreamMetadata::default(),
+        )
+        .unwrap();
+        assert_eq!("Special", playlist);
+
+        let overrides = VideoOverrides {
+            playlist_name: Some("My playlist".to_string()),
+            ..overrides
+        };
+        let playlist = create_youtube_title(
+            &x,
+            &user,
+            Location::Playlist,
+            &overrides,
+            &[],
+            &StreamMetadata::default(),
+        )
+        .unwrap();
+        assert_eq!("My playlist", playlist);
+    }
+
+    #[test]
+    fn test_fit_description() {
+        use super::{fit_description, YOUTUBE_DESCRIPTION_MAX_LENGTH};
+        assert_eq!(
+            "desc\n\nchapters\n\n#tag",
+            fit_description("desc".to_string(), &["chapters", "", "#tag"])
+        );
+
+        let long = "a".repeat(YOUTUBE_DESCRIPTION_MAX_LENGTH - 10);
+        let description = fit_description(long.clone(), &["0:00 a long chapter list", "#tag"]);
+        assert_eq!(format!("{}\n\n#tag", long), description);
+
+        let description = fit_description("ä".repeat(YOUTUBE_DESCRIPTION_MAX_LENGTH), &[]);
+        assert!(description.len() <= YOUTUBE_DESCRIPTION_MAX_LENGTH);
+        assert!(description.ends_with("ä..."));
+    }
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!("0:05", super::format_duration(5));
+        assert_eq!("2:03", super::format_duration(123));
+        assert_eq!("1:02:03", super::format_duration(3723));
+    }
+    #[test]
+    fn test_part_range() {
+        let (mut x, mut user) = get_test_sample_data();
+        x.duration = 7 * 3600;
+        user.youtube_target_duration = 2 * 3600;
+        assert_eq!((0, 2 * 3600), super::part_range_seconds(&x, &user, 1, &[]));
+        assert_eq!(
+            (6 * 3600, 7 * 3600),
+            super::part_range_seconds(&x, &user, 4, &[])
+        );
+        user.youtube_target_duration = 0;
+        assert_eq!(
+            (7 * 2700, 7 * 3600),
+            super::part_range_seconds(&x, &user, 4, &[])
+        );
+
+        let durations = [100, 200, 300, 400];
+        assert_eq!(
+            (300, 600),
+            super::part_range_seconds(&x, &user, 3, &durations)
+        );
+        assert_eq!(
+            (600, 1000),
+            super::part_range_seconds(&x, &user, 4, &durations)
+        );
+    }
+    #[test]
+    fn test_substitute_part_range() {
+        let (x, user) = get_test_sample_data();
+        let title = super::substitute(
+            "$$part_ident$$ $$part_range$$".to_string(),
+            &x,
+            &user,
+            Location::Video(2),
+            &[3600, 3700, 60, 60],
+            &StreamMetadata::default(),
+        )
+        .unwrap();
+        assert_eq!("[2/4] 1:00:00 - 2:01:40", title);
+    }
+    #[test]
+    fn test_substitute_stream_metadata() {
+        use super::stream_metadata::MutedSegment;
+        use super::template::{render, TemplateContext};
+        let (mut x, user) = get_test_sample_data();
+        x.duration = 3723;
+        x.youtube_playlist_id = Some("PL1".to_string());
+        let metadata = StreamMetadata {
+            description: Some("desc".to_string()),
+            game: Some("Chess".to_string()),
+            started_at: Some("2023-10-09T05:30:00+00:00".to_string()),
+            muted_segments: vec![MutedSegment {
+                offset: 60,
+                duration: 30,
+            }],
+            ..StreamMetadata::default()
+        };
+        let context = TemplateContext::new(&x, &user, Location::Playlist, &[], &metadata).unwrap();
+        let rendered = render(
+            "$$original_description$$|$$game$$|$$stream_start$$|$$stream_duration$$|\
+            $$muted_notice$$|$$playlist_url$$",
+            &context,
+        )
+        .unwrap();
+        assert_eq!(
+            "desc|Chess|05:30|1:02:03|Twitch muted parts of this stream: 1:00 - 1:30|\
+            https://www.youtube.com/playlist?list=PL1",
+            rendered
+        );
+    }
+
     fn get_test_sample_data() -> (VideosModel, UsersModel) {
         let x = VideosModel {
             part_count: 4,
@@ -0,0 +1,144 @@
+//! Chapters for the description of a part.
+//!
+//! YouTube only shows chapters if the first one starts at 0:00, there are
+//! at least three and every one is at least ten seconds long. Parts that
+//! do not get there get no chapters at all.
+use super::format_duration;
+use super::stream_metadata::StreamChapter;
+
+const MIN_CHAPTERS: usize = 3;
+const MIN_CHAPTER_SECONDS: u64 = 10;
+
+/// Gets the chapters of the part between `start` and `end` of the stream,
+/// as seconds from the start of the part.
+///
+/// The part starts with the chapter that is running at its start, or
+/// `fallback_title` if there is none yet.
+pub(crate) fn chapters_for_part(
+    chapters: &[StreamChapter],
+    start: u64,
+    end: u64,
+    fallback_title: &str,
+) -> Vec<(u64, String)> {
+    let mut sorted: Vec<&StreamChapter> = chapters.iter().collect();
+    sorted.sort_by_key(|chapter| chapter.offset);
+    let initial = sorted
+        .iter()
+        .rev()
+        .find(|chapter| chapter.offset <= start)
+        .map_or(fallback_title, |chapter| chapter.title.as_str());
+
+    let mut result: Vec<(u64, String)> = vec![(0, initial.to_string())];
+    for chapter in sorted
+        .iter()
+        .filter(|chapter| chapter.offset > start && chapter.offset < end)
+    {
+        let at = chapter.offset - start;
+        let last = result.last_mut().expect("there is always a first chapter");
+        if last.1 == chapter.title {
+            continue;
+        }
+        if at - last.0 < MIN_CHAPTER_SECONDS {
+            // the previous chapter would be too short, this one replaces it
+            last.1 = chapter.title.clone();
+            let len = result.len();
+            if len >= 2 && result[len - 2].1 == result[len - 1].1 {
+                result.pop();
+            }
+            continue;
+        }
+        result.push((at, chapter.title.clone()));
+    }
+    let part_length = end.saturating_sub(start);
+    while result.len() > 1
+        && part_length.saturating_sub(result.last().map_or(0, |last| last.0)) < MIN_CHAPTER_SECONDS
+    {
+        result.pop();
+    }
+
+    if result.len() < MIN_CHAPTERS {
+        return Vec::new();
+    }
+    result
+}
+
+/// Formats the chapters like YouTube expects them, one `0:00 Title` per line
+pub(crate) fn format_chapters(chapters: &[(u64, String)]) -> String {
+    chapters
+        .iter()
+        .map(|(at, title)| format!("{} {}", format_duration(*at), title))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn chapter(offset: u64, title: &str) -> StreamChapter {
+        StreamChapter {
+            offset,
+            title: title.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_chapters_offset_into_part() {
+        let chapters = [
+            chapter(0, "Just Chatting"),
+            chapter(3000, "Chess"),
+            chapter(4000, "Go"),
+            chapter(5000, "Chess"),
+        ];
+        let result = chapters_for_part(&chapters, 3600, 7200, "Stream");
+        assert_eq!(
+            vec![
+                (0, "Chess".to_string()),
+                (400, "Go".to_string()),
+                (1400, "Chess".to_string()),
+            ],
+            result
+        );
+        assert_eq!("0:00 Chess\n6:40 Go\n23:20 Chess", format_chapters(&result));
+    }
+
+    #[test]
+    fn test_chapters_need_three() {
+        let chapters = [chapter(0, "Just Chatting"), chapter(100, "Chess")];
+        assert!(chapters_for_part(&chapters, 0, 3600, "Stream").is_empty());
+    }
+
+    #[test]
+    fn test_chapters_fallback_title() {
+        let chapters = [chapter(100, "Chess"), chapter(200, "Go")];
+        let result = chapters_for_part(&chapters, 0, 3600, "Stream");
+        assert_eq!(
+            vec![
+                (0, "Stream".to_string()),
+                (100, "Chess".to_string()),
+                (200, "Go".to_string()),
+            ],
+            result
+        );
+    }
+
+    #[test]
+    fn test_chapters_too_short() {
+        let chapters = [
+            chapter(0, "A"),
+            chapter(100, "B"),
+            chapter(105, "C"),
+            chapter(200, "D"),
+            chapter(3595, "E"),
+        ];
+        let result = chapters_for_part(&chapters, 0, 3600, "Stream");
+        assert_eq!(
+            vec![
+                (0, "A".to_string()),
+                (100, "C".to_string()),
+                (200, "D".to_string()),
+            ],
+            result
+        );
+    }
+}
@@ -0,0 +1,83 @@
+//! Information about the stream that is not in the database.
+//!
+//! It is read from `{download_folder_path}/{video_id}.metadata.json`, which
+//! the downloader (or the operator) writes next to the parts folder. The file
+//! is the contract between them and the uploader, so the field names below
+//! must not change without updating the writers:
+//!
+//! ```json
+//! {
+//!   "description": "the stream description",
+//!   "game": "Just Chatting",
+//!   "started_at": "2024-05-01T18:00:00+00:00",
+//!   "muted_segments": [{ "offset": 3600, "duration": 120 }],
+//!   "part_durations": [7200.0, 7199.5, 1830.2],
+//!   "chapters": [{ "offset": 0, "title": "Just Chatting" }]
+//! }
+//! ```
+//!
+//! Offsets and durations are seconds from the start of the stream. Every
+//! field is optional, unknown fields are ignored and a missing file is fine.
+//! The file is read once per upload, so changes apply to the next upload.
+use crate::prelude::*;
+use crate::CONF;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub(crate) struct StreamMetadata {
+    /// The description of the stream on twitch
+    pub description: Option<String>,
+    /// The game or category that was streamed
+    pub game: Option<String>,
+    /// When the stream started, as RFC 3339
+    pub started_at: Option<String>,
+    /// The segments of the vod that twitch muted
+    pub muted_segments: Vec<MutedSegment>,
+    /// The length of every part in seconds, as the splitter cut them
+    pub part_durations: Vec<f64>,
+    /// Where the game or category changed, or the chapter markers of twitch
+    pub chapters: Vec<StreamChapter>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct MutedSegment {
+    /// Seconds from the start of the stream
+    pub offset: u64,
+    /// In seconds
+    pub duration: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct StreamChapter {
+    /// Seconds from the start of the stream
+    pub offset: u64,
+    pub title: String,
+}
+
+pub(crate) fn get_metadata_path(video_id: i32) -> PathBuf {
+    Path::new(&CONF.download_folder_path).join(format!("{}.metadata.json", video_id))
+}
+
+impl StreamMetadata {
+    /// Reads the metadata of the video. Problems are logged and ignored
+    pub(crate) fn load(video_id: i32) -> Self {
+        let path = get_metadata_path(video_id);
+        let content = match std::fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Self::default(),
+            Err(e) => {
+                warn!("could not read stream metadata {}: {}", path.display(), e);
+                return Self::default();
+            }
+        };
+        match serde_json::from_str(&content) {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                warn!("could not parse stream metadata {}: {}", path.display(), e);
+                Self::default()
+            }
+        }
+    }
+}
@@ -0,0 +1,105 @@
+//! Tags and hashtags of the uploaded parts.
+//!
+//! YouTube allows 500 characters of tags in total. The commas between the
+//! tags count, and tags with a space are counted with the quotes YouTube
+//! puts around them.
+use std::collections::HashSet;
+
+const YOUTUBE_TAGS_MAX_LENGTH: usize = 500;
+/// YouTube ignores every hashtag of a video that has more than 60, and only
+/// shows the first three above the title. Few hashtags are enough.
+const MAX_HASHTAGS: usize = 15;
+
+/// Cleans up the tags, drops duplicates and keeps as many as fit into the
+/// tag budget, in order. A tag that does not fit is skipped, shorter ones
+/// after it can still be added.
+pub(crate) fn build_tags(tags: impl IntoIterator<Item = String>) -> Vec<String> {
+    let mut seen = HashSet::new();
+    let mut length = 0;
+    let mut result = Vec::new();
+    for tag in tags {
+        // YouTube rejects angle brackets in tags
+        let tag = tag.replace(['<', '>'], "");
+        let tag = tag.split_whitespace().collect::<Vec<_>>().join(" ");
+        if tag.is_empty() || !seen.insert(tag.to_lowercase()) {
+            continue;
+        }
+        let mut cost = tag.chars().count();
+        if tag.contains(' ') {
+            cost += 2;
+        }
+        if !result.is_empty() {
+            cost += 1;
+        }
+        if length + cost > YOUTUBE_TAGS_MAX_LENGTH {
+            continue;
+        }
+        length += cost;
+        result.push(tag);
+    }
+    result
+}
+
+/// Turns the words into hashtags like `#JustChatting`, one line separated by spaces
+pub(crate) fn format_hashtags<'a>(words: impl IntoIterator<Item = &'a str>) -> String {
+    let mut seen = HashSet::new();
+    words
+        .into_iter()
+        .filter_map(|word| {
+            let hashtag: String = word
+                .split_whitespace()
+                .map(|part| {
+                    let mut chars = part.chars().filter(|c| c.is_alphanumeric() || *c == '_');
+                    match chars.next() {
+                        Some(first) => first.to_uppercase().chain(chars).collect(),
+                        None => String::new(),
+                    }
+                })
+                .collect();
+            if hashtag.is_empty() || !seen.insert(hashtag.to_lowercase()) {
+                return None;
+            }
+            Some(format!("#{}", hashtag))
+        })
+        .take(MAX_HASHTAGS)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_build_tags() {
+        let tags = build_tags(
+            ["twitch", " Just   Chatting ", "Twitch", "", "<b>bold</b>"].map(String::from),
+        );
+        assert_eq!(vec!["twitch", "Just Chatting", "bbold/b"], tags);
+    }
+
+    #[test]
+    fn test_build_tags_budget() {
+        let long = "a".repeat(300);
+        let spaced = format!("{} b", "c".repeat(196));
+        let tags = build_tags([long.clone(), spaced.clone(), "short".to_string()]);
+        // the spaced tag is 198 long and 200 with the quotes, 300 + 1 + 200 = 501
+        assert_eq!(vec![long.clone(), "short".to_string()], tags);
+
+        let spaced = format!("{} b", "c".repeat(195));
+        // 197 long and 199 with the quotes, 300 + 1 + 199 = 500
+        let tags = build_tags([long.clone(), spaced.clone()]);
+        assert_eq!(vec![long, spaced], tags);
+    }
+
+    #[test]
+    fn test_format_hashtags() {
+        assert_eq!(
+            "#JustChatting #Streamer #Café",
+            format_hashtags(["just chatting", "Streamer", "streamer", "!!", "café"])
+        );
+        let words: Vec<String> = (0..20).map(|i| format!("word{}", i)).collect();
+        let hashtags = format_hashtags(words.iter().map(String::as_str));
+        assert_eq!(MAX_HASHTAGS, hashtags.split(' ').count());
+    }
+}
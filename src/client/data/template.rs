@@ -0,0 +1,294 @@
+//! Renders the title and description templates with minijinja.
+//!
+//! Templates from before the engine used `$$placeholder$$`. Those are
+//! rewritten to `{{ placeholder }}` before rendering, so they keep working
+//! and can be mixed with the new syntax.
+use super::chapters::{chapters_for_part, format_chapters};
+use super::stream_metadata::{MutedSegment, StreamMetadata};
+use super::tags::format_hashtags;
+use super::{
+    format_duration, format_progress, get_date_prefix, parse_date, part_range_seconds, Location,
+};
+use crate::prelude::*;
+use crate::UPLOADER_CONF;
+use chrono::format::{Item, StrftimeItems};
+use minijinja::{Environment, ErrorKind, UndefinedBehavior};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+use twba_local_db::prelude::{UsersModel, VideosModel};
+
+/// Everything a template can use
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct TemplateContext {
+    pub original_title: String,
+    pub original_description: String,
+    /// The date of the stream in the timezone of the user, like `2023-10-09 05:33:59 +00:00`
+    pub upload_date: String,
+    /// Like `2023-10-09`
+    pub upload_date_short: String,
+    /// The date as RFC 3339, for the `date` filter
+    pub upload_datetime: String,
+    pub twitch_url: String,
+    pub twitch_channel_name: String,
+    pub twitch_channel_url: String,
+    pub part_count: usize,
+    /// The current part, not set for the playlist
+    pub part: Option<usize>,
+    /// Like `[1/4]`, empty for the playlist or if there is only one part
+    pub part_ident: String,
+    pub parts: Vec<PartContext>,
+    /// Either `video` or `playlist`
+    pub location: &'static str,
+    /// The configured variables, with the profile of the user applied
+    pub vars: HashMap<String, String>,
+    /// The length of the whole stream, like `3:25:07`
+    pub stream_duration: String,
+    /// The length of the current part, empty for the playlist
+    pub part_duration: String,
+    /// Where the current part starts in the stream, like `2:00:00`. Empty for the playlist
+    pub part_start: String,
+    /// Where the current part ends in the stream. Empty for the playlist
+    pub part_end: String,
+    /// Like `2:00:00 - 4:00:00`, empty for the playlist
+    pub part_range: String,
+    /// The time the stream started in the timezone of the user, like `17:04`
+    pub stream_start: String,
+    /// The start as RFC 3339, for the `date` filter
+    pub stream_start_datetime: String,
+    pub twitch_vod_id: String,
+    /// The game or category, empty if it is not known
+    pub game: String,
+    /// Tells viewers which parts twitch muted, empty if nothing was muted
+    pub muted_notice: String,
+    /// Empty until the playlist was created
+    pub playlist_url: String,
+    /// The chapters of the current part, one `0:00 Title` per line. Empty for
+    /// the playlist or if the part does not have enough chapters for YouTube
+    pub chapters: String,
+    /// Hashtags of the channel name, the game and the configured extra
+    /// hashtags, like `#Streamer #JustChatting`. Empty for the playlist
+    pub hashtags: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct PartContext {
+    pub number: usize,
+    pub ident: String,
+    pub duration: String,
+    pub start: String,
+    pub end: String,
+    pub range: String,
+}
+
+impl TemplateContext {
+    /// Creates the context. The part durations are used for the part times
+    /// if there is one for every part, otherwise those are estimated.
+    pub(crate) fn new(
+        video: &VideosModel,
+        user: &UsersModel,
+        target: Location,
+        part_durations: &[u64],
+        metadata: &StreamMetadata,
+    ) -> Result<Self> {
+        let date = parse_date(&video.created_at).map_err(UploaderError::ParseDate)?;
+        let timezone =
+            chrono::FixedOffset::from_str(&user.timezone).map_err(UploaderError::ParseDate)?;
+        let date = date.with_timezone(&timezone);
+        let stream_start = match &metadata.started_at {
+            Some(started_at) => parse_date(started_at)
+                .map_err(UploaderError::ParseDate)?
+                .with_timezone(&timezone),
+            None => date,
+        };
+        let part_count = video.part_count.max(0) as usize;
+        let part_context = |number: usize| {
+            let (start, end) = part_range_seconds(video, user, number, part_durations);
+            let (start_text, end_text) = (format_duration(start), format_duration(end));
+            PartContext {
+                number,
+                ident: if part_count > 1 {
+                    format_progress(part_count, number)
+                } else {
+                    String::new()
+                },
+                duration: format_duration(end - start),
+                range: format!("{} - {}", start_text, end_text),
+                start: start_text,
+                end: end_text,
+            }
+        };
+        let (part, location) = match target {
+            Location::Video(part) => (Some(part), "video"),
+            Location::Playlist => (None, "playlist"),
+        };
+        let chapters = part
+            .map(|part| {
+                let (start, end) = part_range_seconds(video, user, part, part_durations);
+                format_chapters(&chapters_for_part(
+                    &metadata.chapters,
+                    start,
+                    end,
+                    &video.name,
+                ))
+            })
+            .unwrap_or_default();
+        let conf = UPLOADER_CONF.metadata_for(user.id);
+        let hashtags = match part {
+            Some(_) => {
+                let mut words = vec![user.twitch_name.as_str()];
+                words.extend(metadata.game.as_deref());
+                words.extend(conf.extra_hashtags.iter().map(String::as_str));
+                format_hashtags(words)
+            }
+            None => String::new(),
+        };
+        let current = part.map(part_context);
+        let current_field =
+            |get: fn(PartContext) -> String| current.clone().map(get).unwrap_or_default();
+        Ok(Self {
+            original_title: video.name.clone(),
+            original_description: metadata.description.clone().unwrap_or_default(),
+            upload_date: date.to_string(),
+            upload_date_short: get_date_prefix(date.date_naive()),
+            upload_datetime: date.to_rfc3339(),
+            twitch_url: video.twitch_download_url.clone().unwrap_or_default(),
+            twitch_channel_name: user.twitch_name.clone(),
+            twitch_channel_url: format!("https://twitch.tv/{}", user.twitch_id),
+            part_count,
+            part,
+            part_ident: current_field(|p| p.ident),
+            part_duration: current_field(|p| p.duration),
+            part_start: current_field(|p| p.start),
+            part_end: current_field(|p| p.end),
+            part_range: current_field(|p| p.range),
+            parts: (1..=part_count).map(part_context).collect(),
+            location,
+            vars: conf.vars,
+            stream_duration: format_duration(video.duration.max(0) as u64),
+            stream_start: stream_start.format("%H:%M").to_string(),
+            stream_start_datetime: stream_start.to_rfc3339(),
+            twitch_vod_id: video.twitch_id.clone(),
+            game: metadata.game.clone().unwrap_or_default(),
+            muted_notice: muted_notice(&metadata.muted_segments),
+            playlist_url: video
+                .youtube_playlist_id
+                .as_ref()
+                .map(|id| format!("https://www.youtube.com/playlist?list={}", id))
+                .unwrap_or_default(),
+            chapters,
+            hashtags,
+        })
+    }
+}
+
+fn muted_notice(segments: &[MutedSegment]) -> String {
+    if segments.is_empty() {
+        return String::new();
+    }
+    let segments: Vec<String> = segments
+        .iter()
+        .map(|segment| {
+            format!(
+                "{} - {}",
+                format_duration(segment.offset),
+                format_duration(segment.offset + segment.duration)
+            )
+        })
+        .collect();
+    format!("Twitch muted parts of this stream: {}", segments.join(", "))
+}
+
+/// Renders the template. Unknown placeholders are an error.
+pub(crate) fn render(template: &str, context: &TemplateContext) -> Result<String> {
+    let mut env = Environment::new();
+    env.set_undefined_behavior(UndefinedBehavior::Strict);
+    env.set_keep_trailing_newline(true);
+    env.add_filter("date", format_date);
+    env.render_str(&convert_legacy_placeholders(template), context)
+        .map_err(UploaderError::RenderTemplate)
+}
+
+/// The variables the template reads from the context, like `chapters`
+pub(crate) fn referenced_variables(template: &str) -> Result<HashSet<String>> {
+    let env = Environment::new();
+    let source = convert_legacy_placeholders(template);
+    let template = env
+        .template_from_str(&source)
+        .map_err(UploaderError::RenderTemplate)?;
+    Ok(template.undeclared_variables(false))
+}
+
+/// Formats an RFC 3339 date with a chrono format string, like `date("%d.%m.%Y")`
+fn format_date(value: String, format: String) -> StdResult<String, minijinja::Error> {
+    let date = chrono::DateTime::parse_from_rfc3339(&value).map_err(|e| {
+        minijinja::Error::new(ErrorKind::InvalidOperation, "value is not a date").with_source(e)
+    })?;
+    let items: Vec<Item> = StrftimeItems::new(&format).collect();
+    if items.iter().any(|item| matches!(item, Item::Error)) {
+        return Err(minijinja::Error::new(
+            ErrorKind::InvalidOperation,
+            format!("invalid date format: {}", format),
+        ));
+    }
+    Ok(date.format_with_items(items.into_iter()).to_string())
+}
+
+/// Rewrites `$$name$$` to `{{ name }}`. Anything else is left alone
+fn convert_legacy_placeholders(template: &str) -> String {
+    let mut result = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("$$") {
+        let after = &rest[start + 2..];
+        let name_len = after
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .unwrap_or(after.len());
+        if name_len > 0 && after[name_len..].starts_with("$$") {
+            result.push_str(&rest[..start]);
+            result.push_str("{{ ");
+            result.push_str(&after[..name_len]);
+            result.push_str(" }}");
+            rest = &after[name_len + 2..];
+        } else {
+            result.push_str(&rest[..start + 2]);
+            rest = after;
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::{convert_legacy_placeholders, referenced_variables};
+
+    #[test]
+    fn test_convert_legacy_placeholders() {
+        assert_eq!(
+            "[{{ upload_date_short }}]{{ part_ident }} {{ original_title }}",
+            convert_legacy_placeholders("[$$upload_date_short$$]$$part_ident$$ $$original_title$$")
+        );
+        assert_eq!("costs 5$$ $$", convert_legacy_placeholders("costs 5$$ $$"));
+        assert_eq!("$${{ a }}", convert_legacy_placeholders("$$$$a$$"));
+        assert_eq!(
+            "{{ a }}{% if x %}",
+            convert_legacy_placeholders("$$a$${% if x %}")
+        );
+    }
+
+    #[test]
+    fn test_referenced_variables() {
+        let used = referenced_variables("{{ original_title }}\n$$hashtags$$").unwrap();
+        assert!(used.contains("original_title"));
+        assert!(used.contains("hashtags"));
+        assert!(!used.contains("chapters"));
+
+        let used =
+            referenced_variables("the chapters are below{% if part %}{{ chapters }}{% endif %}")
+                .unwrap();
+        assert!(used.contains("chapters"));
+        let used = referenced_variables("see the chapters and hashtags below").unwrap();
+        assert!(!used.contains("chapters"));
+        assert!(!used.contains("hashtags"));
+    }
+}
@@ -0,0 +1,121 @@
+use super::data::stream_metadata::StreamMetadata;
+use super::UploaderClient;
+use crate::prelude::*;
+use crate::{store, UPLOADER_CONF};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use tokio::process::Command;
+use tracing::instrument;
+use twba_local_db::prelude::*;
+
+impl UploaderClient {
+    /// Gets the length of every part in seconds.
+    ///
+    /// The lengths come from the splitter sidecar if it has them. Otherwise
+    /// the part files are probed with ffprobe and the results are kept, since
+    /// the files are deleted after their upload. Returns an empty list if
+    /// not every part is known, then the lengths are estimated.
+    #[instrument(skip(self, video, parts, stream), fields(id=video.id))]
+    pub(super) async fn get_part_durations(
+        &self,
+        video: &VideosModel,
+        parts: &[(PathBuf, usize)],
+        stream: &StreamMetadata,
+    ) -> Vec<u64> {
+        let part_count = video.part_count.max(0) as usize;
+        if stream.part_durations.len() == part_count {
+            return stream
+                .part_durations
+                .iter()
+                .map(|seconds| seconds.max(0.0).round() as u64)
+                .collect();
+        }
+
+        let mut known: HashMap<usize, i64> =
+            match store::get_part_durations(&self.db, video.id).await {
+                Ok(durations) => durations
+                    .into_iter()
+                    .map(|duration| (duration.part as usize, duration.duration_ms))
+                    .collect(),
+                Err(e) => {
+                    warn!("could not read part durations of video {}: {}", video.id, e);
+                    HashMap::new()
+                }
+            };
+        for (path, part) in parts {
+            if known.contains_key(part) {
+                continue;
+            }
+            let Some(seconds) = probe_duration(path).await else {
+                continue;
+            };
+            let duration_ms = (seconds * 1000.0).round() as i64;
+            if let Err(e) =
+                store::set_part_duration(&self.db, video.id, *part as i32, duration_ms).await
+            {
+                warn!("could not store duration of part {}: {}", part, e);
+            }
+            known.insert(*part, duration_ms);
+        }
+
+        let durations: Option<Vec<u64>> = (1..=part_count)
+            .map(|part| {
+                known
+                    .get(&part)
+                    .map(|ms| (*ms as f64 / 1000.0).round() as u64)
+            })
+            .collect();
+        durations.unwrap_or_else(|| {
+            debug!(
+                "not every part length of video {} is known, estimating them",
+                video.id
+            );
+            Vec::new()
+        })
+    }
+}
+
+/// Gets the length of the media file in seconds. Failures are logged
+async fn probe_duration(path: &Path) -> Option<f64> {
+    let ffprobe = UPLOADER_CONF.ffprobe_path.as_deref().unwrap_or("ffprobe");
+    let output = Command::new(ffprobe)
+        .args([
+            "-v",
+            "error",
+            "-show_entries",
+            "format=duration",
+            "-of",
+            "default=noprint_wrappers=1:nokey=1",
+        ])
+        .arg(path)
+        .output()
+        .await;
+    let output = match output {
+        Ok(output) if output.status.success() => output,
+        Ok(output) => {
+            warn!(
+                "ffprobe failed for {}: {}",
+                path.display(),
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+            return None;
+        }
+        Err(e) => {
+            warn!("could not run ffprobe for {}: {}", path.display(), e);
+            return None;
+        }
+    };
+    let duration = String::from_utf8_lossy(&output.stdout);
+    match duration.trim().parse::<f64>() {
+        Ok(duration) => Some(duration),
+        Err(e) => {
+            warn!(
+                "could not parse the duration of {} ({}): {}",
+                path.display(),
+                duration.trim(),
+                e
+            );
+            None
+        }
+    }
+}
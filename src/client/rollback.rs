@@ -0,0 +1,173 @@
+use super::quota_cost;
+use super::UploaderClient;
+use crate::prelude::*;
+use crate::CONF;
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+use tracing::instrument;
+use twba_local_db::prelude::*;
+use twba_local_db::re_exports::sea_orm::{
+    ActiveModelTrait, ActiveValue, ColumnTrait, EntityTrait, IntoActiveModel, QueryFilter,
+};
+
+/// Everything that a rollback of a video would delete
+#[derive(Debug, Clone)]
+pub(crate) struct RollbackPlan {
+    pub video: VideosModel,
+    pub playlist_id: Option<String>,
+    /// The uploaded parts as `(part, youtube video id)`
+    pub youtube_video_ids: Vec<(i32, String)>,
+    pub upload_rows: usize,
+}
+
+impl Display for RollbackPlan {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "Rollback of video {}: '{}' (status: {:?})",
+            self.video.id, self.video.name, self.video.status
+        )?;
+        match &self.playlist_id {
+            Some(playlist_id) => writeln!(f, "  delete playlist: {}", playlist_id)?,
+            None => writeln!(f, "  no playlist to delete")?,
+        }
+        for (part, youtube_id) in &self.youtube_video_ids {
+            writeln!(f, "  delete video of part {}: {}", part, youtube_id)?;
+        }
+        write!(
+            f,
+            "  remove {} upload rows and reset the video for a fresh upload",
+            self.upload_rows
+        )
+    }
+}
+
+/// Whether a video with the status can be rolled back without forcing it.
+///
+/// Only videos that did not finish uploading have leftovers to clean up,
+/// finished ones are published and must not be deleted by accident.
+fn can_roll_back(status: &Status) -> bool {
+    matches!(
+        status,
+        Status::Split | Status::Uploading | Status::PartiallyUploaded
+    )
+}
+
+impl UploaderClient {
+    /// Plans the rollback of the video. Videos that finished uploading are
+    /// refused unless `force` is set.
+    #[instrument(skip(self))]
+    pub(crate) async fn plan_rollback(&self, video_id: i32, force: bool) -> Result<RollbackPlan> {
+        let video = Videos::find_by_id(video_id)
+            .one(&self.db)
+            .await?
+            .ok_or(UploaderError::UnknownVideo(video_id))?;
+        if !force && !can_roll_back(&video.status) {
+            return Err(UploaderError::RollbackRefused(
+                video_id,
+                format!("{:?}", video.status),
+            ));
+        }
+        let uploads = VideoUpload::find()
+            .filter(VideoUploadColumn::VideoId.eq(video_id))
+            .all(&self.db)
+            .await?;
+        let youtube_video_ids = uploads
+            .iter()
+            .filter_map(|upload| {
+                upload
+                    .youtube_video_id
+                    .clone()
+                    .map(|youtube_id| (upload.part, youtube_id))
+            })
+            .collect();
+        Ok(RollbackPlan {
+            playlist_id: video.youtube_playlist_id.clone(),
+            video,
+            youtube_video_ids,
+            upload_rows: uploads.len(),
+        })
+    }
+
+    /// Deletes the YouTube resources of the plan and resets the database rows.
+    ///
+    /// Resources that are already gone on YouTube are skipped. If anything else
+    /// fails, the database is left untouched so the rollback can be retried.
+    #[instrument(skip(self, plan), fields(id=plan.video.id))]
+    pub(crate) async fn execute_rollback(&self, plan: &RollbackPlan) -> Result<()> {
+        let video = &plan.video;
+        let user = self.get_user_for_video(video).await?;
+        let client = self.get_client_for_user_blocking(&user).await?;
+
+        for (part, youtube_id) in &plan.youtube_video_ids {
+            info!(
+                "deleting part {} of video {}: {}",
+                part, video.id, youtube_id
+            );
+            let deleted = client.delete_video(youtube_id).await;
+            self.record_quota(video.user_id, quota_cost::DELETE).await;
+            deleted?;
+        }
+        if let Some(playlist_id) = &plan.playlist_id {
+            info!("deleting playlist of video {}: {}", video.id, playlist_id);
+            let deleted = client.delete_playlist(playlist_id).await;
+            self.record_quota(video.user_id, quota_cost::DELETE).await;
+            deleted?;
+        }
+
+        trace!("removing upload rows of video {}", video.id);
+        VideoUpload::delete_many()
+            .filter(VideoUploadColumn::VideoId.eq(video.id))
+            .exec(&self.db)
+            .await?;
+
+        let mut active_video = video.clone().into_active_model();
+        active_video.youtube_playlist_id = ActiveValue::Set(None);
+        active_video.youtube_playlist_created_at = ActiveValue::Set(None);
+        active_video.fail_count = ActiveValue::Set(0);
+        active_video.fail_reason = ActiveValue::Set(None);
+        active_video.status = ActiveValue::Set(Status::Split);
+        active_video
+            .update(&self.db)
+            .await
+            .map_err(UploaderError::SaveVideoStatus)?;
+
+        self.warn_about_missing_part_files(video);
+        info!("rolled back video {}", video.id);
+        Ok(())
+    }
+
+    /// Part files get deleted after they are uploaded, so a rolled back video
+    /// might not have all of its parts anymore.
+    fn warn_about_missing_part_files(&self, video: &VideosModel) {
+        let parts_folder_path = Path::new(&CONF.download_folder_path).join(video.id.to_string());
+        let existing = parts_folder_path
+            .read_dir()
+            .map(|entries| entries.count())
+            .unwrap_or(0);
+        if existing < video.part_count as usize {
+            warn!(
+                "only {} of {} part files exist for video {} in '{}'. \
+                The missing parts have to be created again before the upload can succeed",
+                existing,
+                video.part_count,
+                video.id,
+                parts_folder_path.display()
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::can_roll_back;
+    use twba_local_db::prelude::Status;
+
+    #[test]
+    fn test_can_roll_back() {
+        assert!(can_roll_back(&Status::Split));
+        assert!(can_roll_back(&Status::Uploading));
+        assert!(can_roll_back(&Status::PartiallyUploaded));
+        assert!(!can_roll_back(&Status::Uploaded));
+    }
+}
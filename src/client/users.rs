@@ -0,0 +1,300 @@
+use super::data::stream_metadata::StreamMetadata;
+use super::data::{create_youtube_description, create_youtube_title, Location};
+use super::youtube::{remove_cached_token, revoke_cached_token, LongUploadsStatus};
+use super::UploaderClient;
+use crate::notification::send_notification;
+use crate::overrides::VideoOverrides;
+use crate::prelude::*;
+use crate::store;
+use chrono::Utc;
+use std::fmt::{Display, Formatter};
+use tracing::instrument;
+use twba_local_db::entities::users;
+use twba_local_db::prelude::*;
+use twba_local_db::re_exports::sea_orm::{
+    ActiveModelTrait, ActiveValue, ColumnTrait, Condition, EntityTrait, IntoActiveModel,
+    QueryFilter, TryIntoModel,
+};
+
+/// The part count of the video that the templates are rendered with
+const SAMPLE_PART_COUNT: i32 = 3;
+
+/// The data a new user is created with
+#[derive(Debug, Clone)]
+pub(crate) struct NewUser {
+    pub twitch_id: String,
+    pub twitch_name: String,
+    pub youtube_id: String,
+    pub timezone: String,
+    pub youtube_target_duration: i32,
+    pub youtube_max_duration: i32,
+}
+
+/// What was checked while adding a user
+#[derive(Debug, Clone)]
+pub(crate) struct AddedUser {
+    pub user: UsersModel,
+    pub long_uploads: LongUploadsStatus,
+    /// The rendered templates as `(location, title, description)`
+    pub samples: Vec<(String, String, String)>,
+}
+
+impl Display for AddedUser {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "Added user {}: {} ({}) -> {} ({})",
+            self.user.id,
+            self.user.twitch_name,
+            self.user.twitch_id,
+            self.user.youtube_name,
+            self.user.youtube_id
+        )?;
+        writeln!(f, "long uploads: {}", self.long_uploads)?;
+        for (location, title, description) in &self.samples {
+            writeln!(f)?;
+            writeln!(f, "{} title: {}", location, title)?;
+            writeln!(f, "{} description:", location)?;
+            writeln!(f, "{}", description)?;
+        }
+        Ok(())
+    }
+}
+
+impl UploaderClient {
+    /// Creates a user and makes sure everything is ready for their uploads.
+    ///
+    /// The user stays inactive until the authentication, the channel and the
+    /// templates have been checked, so a failed onboarding never gets picked
+    /// up by a run. An inactive user with the same ids is reused.
+    #[instrument(skip(self))]
+    pub(crate) async fn add_user(&self, new_user: NewUser) -> Result<AddedUser> {
+        let user = self.create_inactive_user(&new_user).await?;
+        info!("created user {}, authenticating", user.id);
+
+        let client = self.get_client_for_user_blocking(&user).await?;
+        let long_uploads = client.long_uploads();
+
+        // reload, the authentication updates the youtube name
+        let user = Users::find_by_id(user.id)
+            .one(&self.db)
+            .await?
+            .ok_or(UploaderError::UnknownUser(user.id))?;
+        let samples = render_samples(&user)?;
+
+        let mut active_user = user.clone().into_active_model();
+        active_user.active = ActiveValue::Set(true);
+        let user = active_user.update(&self.db).await?;
+        info!("user {} is active", user.id);
+        Ok(AddedUser {
+            user,
+            long_uploads,
+            samples,
+        })
+    }
+
+    async fn create_inactive_user(&self, new_user: &NewUser) -> Result<UsersModel> {
+        let matches = Users::find()
+            .filter(
+                Condition::any()
+                    .add(UsersColumn::TwitchId.eq(&new_user.twitch_id))
+                    .add(UsersColumn::YoutubeId.eq(&new_user.youtube_id)),
+            )
+            .all(&self.db)
+            .await?;
+        let mut user = match find_existing_user(matches, new_user)? {
+            Some(user) if user.active => {
+                return Err(UploaderError::UserExists(format!(
+                    "{} ({})",
+                    user.twitch_name, user.youtube_id
+                )))
+            }
+            Some(user) => {
+                info!("reusing inactive user {}", user.id);
+                user.into_active_model()
+            }
+            None => users::ActiveModel {
+                youtube_name: ActiveValue::Set(String::new()),
+                twitch_profile_image_url: ActiveValue::Set(None),
+                youtube_profile_image_url: ActiveValue::Set(None),
+                ..Default::default()
+            },
+        };
+        user.twitch_id = ActiveValue::Set(new_user.twitch_id.clone());
+        user.twitch_name = ActiveValue::Set(new_user.twitch_name.clone());
+        user.youtube_id = ActiveValue::Set(new_user.youtube_id.clone());
+        user.timezone = ActiveValue::Set(new_user.timezone.clone());
+        user.youtube_target_duration = ActiveValue::Set(new_user.youtube_target_duration);
+        user.youtube_max_duration = ActiveValue::Set(new_user.youtube_max_duration);
+        user.active = ActiveValue::Set(false);
+        Ok(user.save(&self.db).await?.try_into_model()?)
+    }
+
+    /// Revokes the token of the user, deletes it and deactivates the user.
+    ///
+    /// A failed revocation does not stop the removal, the notification asks
+    /// to revoke the access by hand instead. The videos of the user are kept,
+    /// so they can still be looked at.
+    #[instrument(skip(self))]
+    pub(crate) async fn remove_user(&self, user_id: i32) -> Result<()> {
+        let user = Users::find_by_id(user_id)
+            .one(&self.db)
+            .await?
+            .ok_or(UploaderError::UnknownUser(user_id))?;
+
+        let revoked = revoke_cached_token(&self.db, &user.youtube_id).await;
+        let revoke_note = revoke_note(&revoked);
+        match revoked {
+            Ok(true) => {}
+            Ok(false) => info!("user {} had no token to revoke", user.id),
+            Err(e) => error!("could not revoke the token of user {}: {}", user.id, e),
+        }
+        remove_cached_token(&self.db, &user.youtube_id).await?;
+        self.youtube_clients
+            .lock()
+            .expect("client lock poisoned")
+            .remove(&user.id);
+        store::clear_needs_reauth(&self.db, user.id).await?;
+
+        let mut active_user = user.clone().into_active_model();
+        active_user.active = ActiveValue::Set(false);
+        active_user.update(&self.db).await?;
+        info!("removed user {}", user.id);
+        send_notification(format!(
+            "Removed {} ({}) from the uploader{}",
+            user.twitch_name, user.youtube_id, revoke_note
+        ))
+        .await;
+        Ok(())
+    }
+}
+
+/// Finds the user the new user replaces, out of the users that have its
+/// twitch id or youtube id.
+///
+/// Both ids have to belong to the same user, otherwise reusing it would
+/// silently move the ids of another user.
+fn find_existing_user(matches: Vec<UsersModel>, new_user: &NewUser) -> Result<Option<UsersModel>> {
+    let disagrees = |user: &UsersModel| {
+        user.twitch_id != new_user.twitch_id || user.youtube_id != new_user.youtube_id
+    };
+    if matches.len() > 1 || matches.iter().any(disagrees) {
+        let users = matches
+            .iter()
+            .map(|user| format!("{} ({}, {})", user.id, user.twitch_id, user.youtube_id))
+            .collect::<Vec<_>>()
+            .join(", ");
+        return Err(UploaderError::UserConflict(users));
+    }
+    Ok(matches.into_iter().next())
+}
+
+/// The part of the removal notification about the revocation of the token
+fn revoke_note(revoked: &Result<bool>) -> String {
+    match revoked {
+        Ok(_) => String::new(),
+        Err(e) => format!(
+            "\nThe token could not be revoked ({}), remove the access at \
+            https://myaccount.google.com/permissions",
+            e
+        ),
+    }
+}
+
+/// Renders the title and description templates for a made up video of the user
+fn render_samples(user: &UsersModel) -> Result<Vec<(String, String, String)>> {
+    let video = sample_video(user);
+    let stream = StreamMetadata::default();
+    let mut samples = Vec::new();
+    for (name, location) in [
+        ("playlist", Location::Playlist),
+        ("part 1", Location::Video(1)),
+    ] {
+        let overrides = VideoOverrides::default();
+        let title = create_youtube_title(&video, user, location, &overrides, &[], &stream)?;
+        let description =
+            create_youtube_description(&video, user, location, &overrides, &[], &stream)?;
+        samples.push((name.to_string(), title, description));
+    }
+    Ok(samples)
+}
+
+fn sample_video(user: &UsersModel) -> VideosModel {
+    VideosModel {
+        id: 0,
+        status: Status::Split,
+        user_id: user.id,
+        name: format!("Sample stream of {}", user.twitch_name),
+        created_at: Utc::now().to_rfc3339(),
+        part_count: SAMPLE_PART_COUNT,
+        duration: user.youtube_target_duration * SAMPLE_PART_COUNT,
+        twitch_id: "0".to_string(),
+        twitch_preview_image_url: None,
+        twitch_download_url: None,
+        youtube_id: None,
+        youtube_playlist_name: String::new(),
+        youtube_preview_image_url: None,
+        youtube_playlist_id: None,
+        youtube_playlist_created_at: None,
+        fail_count: 0,
+        fail_reason: None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn new_user() -> NewUser {
+        NewUser {
+            twitch_id: "t1".to_string(),
+            twitch_name: "name".to_string(),
+            youtube_id: "y1".to_string(),
+            timezone: "+00:00".to_string(),
+            youtube_target_duration: 3600,
+            youtube_max_duration: 7200,
+        }
+    }
+
+    fn user(id: i32, twitch_id: &str, youtube_id: &str) -> UsersModel {
+        UsersModel {
+            id,
+            twitch_id: twitch_id.to_string(),
+            twitch_name: String::new(),
+            twitch_profile_image_url: None,
+            youtube_id: youtube_id.to_string(),
+            youtube_name: String::new(),
+            youtube_profile_image_url: None,
+            youtube_target_duration: 3600,
+            youtube_max_duration: 7200,
+            active: false,
+            timezone: "+00:00".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_find_existing_user() {
+        assert!(find_existing_user(vec![], &new_user()).unwrap().is_none());
+        let existing = find_existing_user(vec![user(1, "t1", "y1")], &new_user()).unwrap();
+        assert_eq!(Some(1), existing.map(|user| user.id));
+    }
+
+    #[test]
+    fn test_find_existing_user_conflict() {
+        let result = find_existing_user(vec![user(1, "t1", "y2")], &new_user());
+        assert!(matches!(result, Err(UploaderError::UserConflict(_))));
+        let result = find_existing_user(vec![user(1, "t2", "y1")], &new_user());
+        assert!(matches!(result, Err(UploaderError::UserConflict(_))));
+        let result =
+            find_existing_user(vec![user(1, "t1", "y2"), user(2, "t2", "y1")], &new_user());
+        assert!(matches!(result, Err(UploaderError::UserConflict(_))));
+    }
+
+    #[test]
+    fn test_revoke_note() {
+        assert_eq!("", revoke_note(&Ok(true)));
+        assert_eq!("", revoke_note(&Ok(false)));
+        let note = revoke_note(&Err(UploaderError::UnknownUser(1)));
+        assert!(note.contains("could not be revoked"));
+    }
+}
@@ -1,5 +1,8 @@
 use crate::client::data::VideoData;
-use crate::prelude::{info, trace, Result, UploaderError};
+use crate::config::{AuthMethod, YoutubeFeature};
+use crate::errors::AuthError;
+use crate::prelude::{info, trace, warn, Result, UploaderError};
+use auth::{AuthState, YoutubeAuthenticator};
 use google_youtube3::{
     api::{
         Playlist, PlaylistItem, PlaylistItemSnippet, PlaylistSnippet, PlaylistStatus, ResourceId,
@@ -8,17 +11,53 @@ use google_youtube3::{
     hyper::{self, client::HttpConnector, Client},
     hyper_rustls::{HttpsConnector, HttpsConnectorBuilder},
 };
+use std::collections::HashSet;
 use std::fmt::{Debug, Formatter};
 use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 use tokio::fs;
+use tokio::task::JoinHandle;
 use tracing::instrument;
+use twba_local_db::re_exports::sea_orm::DatabaseConnection;
 
 mod auth;
+mod auth_code;
+mod db_token_storage;
 mod flow_delegate;
+mod redirect_listener;
+mod token_health;
+mod token_storage;
+
+pub(crate) use auth::{remove_cached_token, revoke_cached_token, validate_redirect_uris};
+pub(crate) use token_health::{check_cached_token, TokenCheck};
+pub(crate) use token_storage::{encrypt_token_cache, EncryptOutcome};
+
+/// The quota costs of the api calls we use.
+///
+/// See <https://developers.google.com/youtube/v3/determine_quota_cost>
+pub(crate) mod quota_cost {
+    pub const VIDEO_INSERT: i64 = 1600;
+    pub const PLAYLIST_INSERT: i64 = 50;
+    pub const PLAYLIST_ITEM_INSERT: i64 = 50;
+    pub const DELETE: i64 = 50;
+    pub const LIST: i64 = 1;
+}
 
 pub struct YoutubeClient {
     //TODO: change this to a thing that does exponential backoff when possible
     client: google_youtube3::YouTube<HttpsConnector<HttpConnector>>,
+    /// Decides which scope a feature needs
+    auth_method: AuthMethod,
+    /// The scopes the token was granted
+    scopes: Vec<Scope>,
+    /// Read from the channel when the client is accepted, with when it was read
+    long_uploads: Mutex<(LongUploadsStatus, Instant)>,
+    /// The region of the channel, the categories are checked for it
+    category_region: String,
+    /// The configured categories that can not be used in the region of the
+    /// channel. `None` until the check worked
+    invalid_categories: Mutex<Option<Vec<u32>>>,
 }
 
 impl YoutubeClient {
@@ -51,7 +90,7 @@ impl YoutubeClient {
                 privacy_status: Some(video_data.video_privacy),
                 public_stats_viewable: Some(true),
                 embeddable: Some(true),
-                self_declared_made_for_kids: Some(false),
+                self_declared_made_for_kids: Some(video_data.made_for_kids),
                 ..Default::default()
             }),
             ..Default::default()
@@ -61,7 +100,11 @@ impl YoutubeClient {
             .await
             .map_err(UploaderError::OpenPartFile)?;
 
-        let insert_call = self.client.videos().insert(video);
+        let insert_call = self
+            .client
+            .videos()
+            .insert(video)
+            .add_scope(self.scope_for(YoutubeFeature::Upload)?);
         trace!("Starting resumable upload");
         let upload = insert_call
             .upload_resumable(
@@ -77,7 +120,7 @@ impl YoutubeClient {
         let result_str = if upload.is_ok() { "Ok" } else { "Error" };
         info!("upload request done with result: {}", result_str);
         upload
-            .map_err(UploaderError::YoutubeError)?
+            .map_err(|e| youtube_error(e, YoutubeFeature::Upload))?
             .1
             .id
             .ok_or(UploaderError::NoIdReturned)
@@ -106,9 +149,10 @@ impl YoutubeClient {
         self.client
             .playlist_items()
             .insert(playlist_item)
+            .add_scope(self.scope_for(YoutubeFeature::Playlists)?)
             .doit()
             .await
-            .map_err(UploaderError::YoutubeError)?;
+            .map_err(|e| youtube_error(e, YoutubeFeature::Playlists))?;
         Ok(())
     }
     #[instrument(skip(self, video))]
@@ -129,35 +173,417 @@ impl YoutubeClient {
             }),
             ..Default::default()
         };
-        let playlist_insert_call = self.client.playlists().insert(playlist);
+        let playlist_insert_call = self
+            .client
+            .playlists()
+            .insert(playlist)
+            .add_scope(self.scope_for(YoutubeFeature::Playlists)?);
         let (_, playlist) = playlist_insert_call
             .doit()
             .await
-            .map_err(UploaderError::YoutubeError)?;
+            .map_err(|e| youtube_error(e, YoutubeFeature::Playlists))?;
 
         playlist.id.ok_or(UploaderError::NoIdReturned)
     }
 }
 
+impl YoutubeClient {
+    /// Deletes a video. A video that does not exist (anymore) is not an error.
+    #[instrument(skip(self))]
+    pub(crate) async fn delete_video(&self, video_id: &str) -> Result<()> {
+        let result = self
+            .client
+            .videos()
+            .delete(video_id)
+            .add_scope(self.scope_for(YoutubeFeature::Delete)?)
+            .doit()
+            .await;
+        skip_not_found(result, YoutubeFeature::Delete, "video", video_id)
+    }
+    /// Deletes a playlist. A playlist that does not exist (anymore) is not an error.
+    #[instrument(skip(self))]
+    pub(crate) async fn delete_playlist(&self, playlist_id: &str) -> Result<()> {
+        let result = self
+            .client
+            .playlists()
+            .delete(playlist_id)
+            .add_scope(self.scope_for(YoutubeFeature::Playlists)?)
+            .doit()
+            .await;
+        skip_not_found(result, YoutubeFeature::Playlists, "playlist", playlist_id)
+    }
+}
+
+/// The channel the client is authenticated for
+#[derive(Debug, Clone)]
+pub(crate) struct OwnChannel {
+    pub id: String,
+    pub title: Option<String>,
+    pub long_uploads: LongUploadsStatus,
+    /// The country set for the channel, like `US`
+    pub country: Option<String>,
+}
+
+/// The region the categories are checked for if the channel has no country
+pub(crate) const DEFAULT_CATEGORY_REGION: &str = "US";
+
+/// The longest video a channel without long uploads can upload
+pub(crate) const SHORT_UPLOAD_MAX_SECONDS: u64 = 15 * 60;
+
+/// How long the long upload status of a channel is trusted before it is read
+/// again, so a channel that got verified is noticed without a restart
+pub(crate) const LONG_UPLOADS_RECHECK: Duration = Duration::from_secs(60 * 60);
+
+/// Whether the channel can upload videos longer than 15 minutes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum LongUploadsStatus {
+    Allowed,
+    /// The channel could get long uploads, but has to verify first
+    Eligible,
+    Disallowed,
+    Unspecified,
+}
+
+impl LongUploadsStatus {
+    /// Whether parts longer than [`SHORT_UPLOAD_MAX_SECONDS`] can be uploaded.
+    ///
+    /// An unspecified status is not held against the channel, YouTube
+    /// rejects the part in the worst case.
+    pub(crate) fn allows_long_uploads(&self) -> bool {
+        !matches!(
+            self,
+            LongUploadsStatus::Eligible | LongUploadsStatus::Disallowed
+        )
+    }
+
+    fn from_api(status: &str) -> Self {
+        match status {
+            "allowed" => LongUploadsStatus::Allowed,
+            "eligible" => LongUploadsStatus::Eligible,
+            "disallowed" => LongUploadsStatus::Disallowed,
+            _ => LongUploadsStatus::Unspecified,
+        }
+    }
+}
+
+impl std::fmt::Display for LongUploadsStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let status = match self {
+            LongUploadsStatus::Allowed => "allowed",
+            LongUploadsStatus::Eligible => "eligible",
+            LongUploadsStatus::Disallowed => "disallowed",
+            LongUploadsStatus::Unspecified => "unspecified",
+        };
+        write!(f, "{}", status)
+    }
+}
+
+impl YoutubeClient {
+    /// Gets the channel of the authenticated account
+    #[instrument(skip(self))]
+    pub(crate) async fn get_own_channel(&self) -> Result<OwnChannel> {
+        let (_, response) = self
+            .client
+            .channels()
+            .list(&vec!["snippet".to_string(), "status".to_string()])
+            .mine(true)
+            .add_scope(self.scope_for(YoutubeFeature::ReadOnly)?)
+            .doit()
+            .await
+            .map_err(|e| youtube_error(e, YoutubeFeature::ReadOnly))?;
+        let channel = response
+            .items
+            .and_then(|items| items.into_iter().next())
+            .ok_or(AuthError::NoChannel)?;
+        // the status is compared by its api name, like google documents it
+        let long_uploads = channel
+            .status
+            .and_then(|status| status.long_uploads_status)
+            .and_then(|status| serde_json::to_value(status).ok())
+            .and_then(|status| status.as_str().map(LongUploadsStatus::from_api))
+            .unwrap_or(LongUploadsStatus::Unspecified);
+        let (title, country) = channel
+            .snippet
+            .map(|snippet| (snippet.title, snippet.country))
+            .unwrap_or_default();
+        Ok(OwnChannel {
+            id: channel.id.ok_or(UploaderError::NoIdReturned)?,
+            title,
+            long_uploads,
+            country,
+        })
+    }
+
+    /// Gets the ids of the categories videos can be uploaded to in the region
+    #[instrument(skip(self))]
+    pub(crate) async fn get_assignable_categories(&self, region: &str) -> Result<HashSet<u32>> {
+        let (_, response) = self
+            .client
+            .video_categories()
+            .list(&vec!["snippet".to_string()])
+            .region_code(region)
+            .add_scope(self.scope_for(YoutubeFeature::ReadOnly)?)
+            .doit()
+            .await
+            .map_err(|e| youtube_error(e, YoutubeFeature::ReadOnly))?;
+        Ok(response
+            .items
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|category| {
+                category
+                    .snippet
+                    .as_ref()
+                    .and_then(|snippet| snippet.assignable)
+                    .unwrap_or(false)
+            })
+            .filter_map(|category| category.id?.parse().ok())
+            .collect())
+    }
+}
+
+fn skip_not_found<T>(
+    result: google_youtube3::Result<T>,
+    feature: YoutubeFeature,
+    kind: &str,
+    id: &str,
+) -> Result<()> {
+    match result {
+        Ok(_) => Ok(()),
+        Err(e) if is_not_found(&e) => {
+            warn!("{} {} does not exist on youtube, skipping", kind, id);
+            Ok(())
+        }
+        Err(e) => Err(youtube_error(e, feature)),
+    }
+}
+
+/// The scope that is needed for a feature.
+///
+/// Google has no scope just for playlists or deleting, so those need full access. The
+/// device flow only allows full and read only access, so uploads need full
+/// access there as well.
+fn feature_scope(feature: YoutubeFeature, method: AuthMethod) -> Scope {
+    match (feature, method) {
+        (YoutubeFeature::Upload, AuthMethod::Installed) => Scope::Upload,
+        (YoutubeFeature::Upload, AuthMethod::Device) => Scope::Full,
+        (YoutubeFeature::Playlists | YoutubeFeature::Delete, _) => Scope::Full,
+        (YoutubeFeature::ReadOnly, _) => Scope::Readonly,
+    }
+}
+
+/// The scopes to request for the configured features of the user
+pub(crate) fn scopes_for_user(user: &str) -> Vec<Scope> {
+    scopes_for(
+        &crate::UPLOADER_CONF.auth.features_for(user),
+        auth::get_auth_method(user),
+    )
+}
+
+/// Whether the granted scopes cover the scope, full access covers all of them
+fn is_scope_granted(granted: &[Scope], scope: &Scope) -> bool {
+    granted.contains(scope) || granted.contains(&Scope::Full)
+}
+
+fn scopes_for(features: &[YoutubeFeature], method: AuthMethod) -> Vec<Scope> {
+    let mut scopes = Vec::new();
+    for feature in features {
+        let scope = feature_scope(*feature, method);
+        if !scopes.contains(&scope) {
+            scopes.push(scope);
+        }
+    }
+    scopes
+}
+
+/// Turns an error of a call for the feature into an uploader error.
+///
+/// A token that lacks the scope of the feature gets its own error, so the
+/// user can be asked to consent to it.
+fn youtube_error(error: google_youtube3::Error, feature: YoutubeFeature) -> UploaderError {
+    if is_insufficient_scope(&error) {
+        warn!("the token has no access for {}: {:?}", feature, error);
+        return UploaderError::InsufficientScope(feature);
+    }
+    UploaderError::YoutubeError(error)
+}
+
+fn is_insufficient_scope(error: &google_youtube3::Error) -> bool {
+    match error {
+        google_youtube3::Error::BadRequest(value) => {
+            let error = &value["error"];
+            let reason_matches = |reason: &serde_json::Value| {
+                matches!(
+                    reason.as_str(),
+                    Some("insufficientPermissions" | "ACCESS_TOKEN_SCOPE_INSUFFICIENT")
+                )
+            };
+            error["code"].as_u64() == Some(403)
+                && (error["errors"]
+                    .as_array()
+                    .is_some_and(|errors| errors.iter().any(|e| reason_matches(&e["reason"])))
+                    || error["details"].as_array().is_some_and(|details| {
+                        details.iter().any(|d| reason_matches(&d["reason"]))
+                    }))
+        }
+        google_youtube3::Error::Failure(response) => {
+            response.status() == hyper::StatusCode::FORBIDDEN
+                && response
+                    .headers()
+                    .get(hyper::header::WWW_AUTHENTICATE)
+                    .and_then(|header| header.to_str().ok())
+                    .is_some_and(|header| header.contains("insufficient_scope"))
+        }
+        _ => false,
+    }
+}
+
+fn is_not_found(error: &google_youtube3::Error) -> bool {
+    match error {
+        google_youtube3::Error::BadRequest(value) => value["error"]["code"].as_u64() == Some(404),
+        google_youtube3::Error::Failure(response) => {
+            response.status() == hyper::StatusCode::NOT_FOUND
+        }
+        _ => false,
+    }
+}
+
 impl Debug for YoutubeClient {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("YoutubeClient").finish()
     }
 }
 
+/// A client that might still be waiting for its user to authenticate
+pub(crate) enum ClientState {
+    Ready(YoutubeClient),
+    /// Finishes once the user authenticated
+    WaitingForUser(JoinHandle<Result<YoutubeClient>>),
+}
+
+impl ClientState {
+    /// Waits until the client is ready
+    pub(crate) async fn wait(self) -> Result<YoutubeClient> {
+        match self {
+            ClientState::Ready(client) => Ok(client),
+            ClientState::WaitingForUser(handle) => handle
+                .await
+                .map_err(|e| UploaderError::AuthError(AuthError::AuthTask(e)))?,
+        }
+    }
+}
+
+/// The path of the client secret from the config
+fn get_client_secret_path() -> Result<PathBuf> {
+    Ok(PathBuf::from(
+        &shellexpand::full(&crate::CONF.google.youtube.client_secret_path)
+            .map_err(UploaderError::ExpandPath)?
+            .to_string(),
+    ))
+}
+
 impl YoutubeClient {
-    #[tracing::instrument]
-    pub async fn new(scopes: &Vec<Scope>, user: Option<String>) -> Result<Self> {
-        let hyper_client = Self::create_hyper_client()?;
-        let application_secret_path = PathBuf::from(
-            &shellexpand::full(&crate::CONF.google.youtube.client_secret_path)
-                .map_err(UploaderError::ExpandPath)?
-                .to_string(),
-        );
+    /// Creates the client for the user.
+    ///
+    /// This does not wait for the user if they have to authenticate
+    /// interactively, see [`ClientState`].
+    #[tracing::instrument(skip(db))]
+    pub async fn new(
+        db: &DatabaseConnection,
+        scopes: &Vec<Scope>,
+        user: Option<String>,
+    ) -> Result<ClientState> {
+        let application_secret_path = get_client_secret_path()?;
+        let auth_method = auth::get_auth_method(user.as_deref().unwrap_or_default());
+        let auth = auth::get_auth(db.clone(), &application_secret_path, scopes, user).await?;
+        let scopes = scopes.clone();
+        match auth {
+            AuthState::Authenticated(auth) => Ok(ClientState::Ready(Self::from_auth(
+                auth,
+                auth_method,
+                scopes,
+            )?)),
+            waiting => Ok(ClientState::WaitingForUser(tokio::spawn(async move {
+                Self::from_auth(waiting.wait().await?, auth_method, scopes)
+            }))),
+        }
+    }
 
-        let auth = auth::get_auth(&application_secret_path, scopes, user).await?;
+    fn from_auth(
+        auth: YoutubeAuthenticator,
+        auth_method: AuthMethod,
+        scopes: Vec<Scope>,
+    ) -> Result<Self> {
+        let hyper_client = Self::create_hyper_client()?;
         let client = google_youtube3::YouTube::new(hyper_client, auth);
-        Ok(Self { client })
+        Ok(Self {
+            client,
+            auth_method,
+            scopes,
+            long_uploads: Mutex::new((LongUploadsStatus::Unspecified, Instant::now())),
+            category_region: DEFAULT_CATEGORY_REGION.to_string(),
+            invalid_categories: Mutex::new(None),
+        })
+    }
+
+    /// The scope for a call of the feature.
+    ///
+    /// Fails if the token was not granted it, requesting it anyway would
+    /// start an interactive authentication in the middle of the call.
+    fn scope_for(&self, feature: YoutubeFeature) -> Result<Scope> {
+        let scope = feature_scope(feature, self.auth_method);
+        if !is_scope_granted(&self.scopes, &scope) {
+            return Err(UploaderError::InsufficientScope(feature));
+        }
+        Ok(scope)
+    }
+
+    pub(crate) fn long_uploads(&self) -> LongUploadsStatus {
+        self.long_uploads
+            .lock()
+            .expect("long uploads lock poisoned")
+            .0
+    }
+    /// How long ago the long upload status was read
+    pub(crate) fn long_uploads_age(&self) -> Duration {
+        self.long_uploads
+            .lock()
+            .expect("long uploads lock poisoned")
+            .1
+            .elapsed()
+    }
+    pub(crate) fn set_long_uploads(&self, long_uploads: LongUploadsStatus) {
+        *self
+            .long_uploads
+            .lock()
+            .expect("long uploads lock poisoned") = (long_uploads, Instant::now());
+    }
+    pub(crate) fn category_region(&self) -> &str {
+        &self.category_region
+    }
+    pub(crate) fn set_category_region(&mut self, region: String) {
+        self.category_region = region;
+    }
+    pub(crate) fn categories_checked(&self) -> bool {
+        self.invalid_categories
+            .lock()
+            .expect("category lock poisoned")
+            .is_some()
+    }
+    /// Whether the category can be used. Every category can be used until
+    /// the check worked
+    pub(crate) fn is_category_valid(&self, category: u32) -> bool {
+        self.invalid_categories
+            .lock()
+            .expect("category lock poisoned")
+            .as_ref()
+            .map_or(true, |invalid| !invalid.contains(&category))
+    }
+    pub(crate) fn set_invalid_categories(&self, invalid_categories: Vec<u32>) {
+        *self
+            .invalid_categories
+            .lock()
+            .expect("category lock poisoned") = Some(invalid_categories);
     }
 
     fn create_hyper_client() -> Result<Client<HttpsConnector<HttpConnector>>> {
@@ -172,3 +598,79 @@ impl YoutubeClient {
         ))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_scopes_for_installed() {
+        let features = [
+            YoutubeFeature::Upload,
+            YoutubeFeature::Playlists,
+            YoutubeFeature::Delete,
+            YoutubeFeature::ReadOnly,
+        ];
+        assert_eq!(
+            vec![Scope::Upload, Scope::Full, Scope::Readonly],
+            scopes_for(&features, AuthMethod::Installed)
+        );
+    }
+
+    #[test]
+    fn test_is_scope_granted() {
+        assert!(is_scope_granted(&[Scope::Upload], &Scope::Upload));
+        assert!(is_scope_granted(&[Scope::Full], &Scope::Upload));
+        assert!(is_scope_granted(&[Scope::Full], &Scope::Readonly));
+        assert!(!is_scope_granted(
+            &[Scope::Upload, Scope::Readonly],
+            &Scope::Full
+        ));
+        assert!(!is_scope_granted(&[], &Scope::Readonly));
+    }
+
+    #[test]
+    fn test_is_insufficient_scope() {
+        let error = google_youtube3::Error::BadRequest(serde_json::json!({
+            "error": {
+                "code": 403,
+                "message": "Request had insufficient authentication scopes.",
+                "errors": [{
+                    "domain": "global",
+                    "reason": "insufficientPermissions",
+                }],
+            }
+        }));
+        assert!(is_insufficient_scope(&error));
+        assert!(matches!(
+            youtube_error(error, YoutubeFeature::Delete),
+            UploaderError::InsufficientScope(YoutubeFeature::Delete)
+        ));
+
+        let error = google_youtube3::Error::BadRequest(serde_json::json!({
+            "error": {
+                "code": 403,
+                "errors": [{ "reason": "quotaExceeded" }],
+            }
+        }));
+        assert!(!is_insufficient_scope(&error));
+    }
+
+    #[test]
+    fn test_scopes_for_device() {
+        assert_eq!(
+            vec![Scope::Full, Scope::Readonly],
+            scopes_for(
+                &[YoutubeFeature::Upload, YoutubeFeature::ReadOnly],
+                AuthMethod::Device
+            )
+        );
+        assert_eq!(
+            vec![Scope::Full],
+            scopes_for(
+                &[YoutubeFeature::Upload, YoutubeFeature::Playlists],
+                AuthMethod::Device
+            )
+        );
+    }
+}
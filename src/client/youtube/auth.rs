@@ -1,21 +1,74 @@
+use crate::client::youtube::db_token_storage::DatabaseTokenStorage;
 use crate::client::youtube::flow_delegate::CustomFlowDelegate;
+use crate::client::youtube::token_storage::{get_token_cipher, EncryptedTokenStorage};
+use crate::config::{AuthMethod, TokenStorageKind};
+use crate::control::CONTROL;
 use crate::errors::{AuthError, PersistentPathError};
 use crate::prelude::*;
+use crate::{store, UPLOADER_CONF};
 use google_youtube3::api::Scope;
-use google_youtube3::oauth2::authenticator::Authenticator;
+use google_youtube3::oauth2::authenticator::{Authenticator, AuthenticatorBuilder};
 use google_youtube3::{hyper::client::HttpConnector, hyper_rustls::HttpsConnector, oauth2};
 use std::collections::HashMap;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use tokio::fs;
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
 use tracing::instrument;
+use twba_local_db::prelude::{Users, UsersColumn};
+use twba_local_db::re_exports::sea_orm::{
+    ColumnTrait, DatabaseConnection, EntityTrait, QueryFilter,
+};
 
 type Result<T> = std::result::Result<T, AuthError>;
-#[instrument]
+pub(super) type YoutubeAuthenticator = Authenticator<HttpsConnector<HttpConnector>>;
+
+/// The result of authenticating a user
+pub(super) enum AuthState {
+    Authenticated(YoutubeAuthenticator),
+    /// The user has to authenticate interactively first.
+    ///
+    /// The flow keeps running in the background and the handle finishes
+    /// once the user did authenticate (or the flow gave up).
+    WaitingForUser(JoinHandle<Result<YoutubeAuthenticator>>),
+}
+
+const GOOGLE_DEVICE_CODE_URL: &str = "https://oauth2.googleapis.com/device/code";
+const GOOGLE_DEVICE_GRANT_TYPE: &str = "urn:ietf:params:oauth:grant-type:device_code";
+const GOOGLE_REVOKE_URL: &str = "https://oauth2.googleapis.com/revoke";
+
+pub(super) fn get_auth_method(user: &str) -> AuthMethod {
+    let conf = &UPLOADER_CONF.auth;
+    conf.user_methods.get(user).copied().unwrap_or(conf.method)
+}
+
+/// Reads the client secret for the auth method.
+///
+/// The device flow uses `device_client_secret_path` if it is set.
+pub(super) async fn read_application_secret(
+    application_secret_path: &Path,
+    method: AuthMethod,
+) -> Result<oauth2::ApplicationSecret> {
+    let application_secret_path = match (method, &UPLOADER_CONF.auth.device_client_secret_path) {
+        (AuthMethod::Device, Some(path)) => PathBuf::from(
+            shellexpand::full(path)
+                .map_err(AuthError::ExpandPath)?
+                .to_string(),
+        ),
+        _ => application_secret_path.to_path_buf(),
+    };
+    oauth2::read_application_secret(application_secret_path)
+        .await
+        .map_err(AuthError::ReadApplicationSecret)
+}
+#[instrument(skip(db))]
 pub(super) async fn get_auth<USER: EasyString>(
+    db: DatabaseConnection,
     application_secret_path: &impl EasyPath,
     scopes: &Vec<Scope>,
     user: Option<USER>,
-) -> Result<Authenticator<HttpsConnector<HttpConnector>>> {
+) -> Result<AuthState> {
     let application_secret_path = application_secret_path.as_ref();
     trace!(
         "getting auth for user: {:?} with scopes: {:?} and secret_path: {:?}",
@@ -24,36 +77,201 @@ pub(super) async fn get_auth<USER: EasyString>(
         application_secret_path
     );
 
-    let app_secret = oauth2::read_application_secret(application_secret_path)
-        .await
-        .map_err(AuthError::ReadApplicationSecret)?;
+    let user_name: String = user.clone().map(|x| x.into()).unwrap_or_default();
+    let method = get_auth_method(&user_name);
+    let app_secret = read_application_secret(application_secret_path, method).await?;
+
+    trace!("creating authenticator with method: {:?}", method);
+    let interaction_needed = Arc::new(Notify::new());
+    let auth = match method {
+        AuthMethod::Installed => {
+            let redirect_uri = get_redirect_uri(&app_secret, user.clone())?;
+            validate_redirect_uri(&redirect_uri, &app_secret.redirect_uris)?;
+            let user: Option<String> = user.map(|x| x.into());
+            let method = oauth2::InstalledFlowReturnMethod::Interactive;
+            let builder = oauth2::InstalledFlowAuthenticator::builder(app_secret, method)
+                .flow_delegate(Box::new(CustomFlowDelegate::new(
+                    user,
+                    redirect_uri,
+                    interaction_needed.clone(),
+                )))
+                .force_account_selection(true);
+            with_token_cache(builder, db, &user_name)
+                .await?
+                .build()
+                .await
+        }
+        AuthMethod::Device => {
+            let user: Option<String> = user.map(|x| x.into());
+            let builder = oauth2::DeviceFlowAuthenticator::builder(app_secret)
+                .device_code_url(GOOGLE_DEVICE_CODE_URL)
+                .grant_type(GOOGLE_DEVICE_GRANT_TYPE)
+                .flow_delegate(Box::new(CustomFlowDelegate::new(
+                    user,
+                    String::new(),
+                    interaction_needed.clone(),
+                )));
+            with_token_cache(builder, db, &user_name)
+                .await?
+                .build()
+                .await
+        }
+    }
+    .map_err(AuthError::CreateAuth)?;
+
+    trace!("got authenticator, requesting scopes");
+    let scopes = scopes.clone();
+    let prompt_user = user_name.clone();
+    let mut token = tokio::spawn(async move {
+        let access_token = auth.token(&scopes).await;
+        CONTROL.remove_auth_prompt(&prompt_user);
+        let access_token = access_token.map_err(|e| AuthError::GetAccessToken(e.into()))?;
+        trace!("got scope access: {:?}", access_token);
+        Ok(auth)
+    });
+    tokio::select! {
+        auth = &mut token => {
+            let auth = auth.map_err(AuthError::AuthTask)??;
+            Ok(AuthState::Authenticated(auth))
+        }
+        _ = interaction_needed.notified() => {
+            info!(
+                "user {} has to authenticate, continuing in the background",
+                user_name
+            );
+            Ok(AuthState::WaitingForUser(token))
+        }
+    }
+}
+
+/// Sets where the tokens of the user are kept.
+///
+/// Tokens are encrypted if a key is configured, in plaintext otherwise.
+async fn with_token_cache<C, F>(
+    builder: AuthenticatorBuilder<C, F>,
+    db: DatabaseConnection,
+    user: &str,
+) -> Result<AuthenticatorBuilder<C, F>> {
+    let cipher = get_token_cipher()?;
+    if UPLOADER_CONF.auth.token_storage == TokenStorageKind::Database {
+        trace!("keeping the tokens of {} in the database", user);
+        let storage = DatabaseTokenStorage::new(db, user.to_string(), cipher);
+        return Ok(builder.with_storage(Box::new(storage)));
+    }
 
     let persistent_path =
-        get_and_validate_persistent_path(&crate::CONF.google.path_auth_cache, user.clone()).await?;
+        get_and_validate_persistent_path(&crate::CONF.google.path_auth_cache, Some(user)).await?;
     trace!(
         "persistent path for auth for user: {:?}: {:?}",
         user,
         &persistent_path
     );
+    Ok(match cipher {
+        Some(cipher) => {
+            trace!("encrypting the token cache");
+            builder.with_storage(Box::new(EncryptedTokenStorage::new(
+                persistent_path,
+                cipher,
+            )))
+        }
+        None => builder.persist_tokens_to_disk(persistent_path),
+    })
+}
 
-    trace!("creating authenticator");
-    let user = user.map(|x| x.into());
-    let method = oauth2::InstalledFlowReturnMethod::Interactive;
-    let auth = oauth2::InstalledFlowAuthenticator::builder(app_secret, method)
-        .flow_delegate(Box::new(CustomFlowDelegate::new(user)))
-        .persist_tokens_to_disk(persistent_path)
-        .force_account_selection(true)
-        .build()
-        .await
-        .map_err(AuthError::CreateAuth)?;
+impl AuthState {
+    /// Waits until the user is authenticated
+    pub(super) async fn wait(self) -> Result<YoutubeAuthenticator> {
+        match self {
+            AuthState::Authenticated(auth) => Ok(auth),
+            AuthState::WaitingForUser(handle) => handle.await.map_err(AuthError::AuthTask)?,
+        }
+    }
+}
 
-    trace!("got authenticator, requesting scopes");
-    let access_token = auth
-        .token(scopes)
-        .await
-        .map_err(|e| AuthError::GetAccessToken(e.into()))?;
-    trace!("got scope access: {:?}", access_token);
-    Ok(auth)
+/// Gets the redirect uri for the user.
+///
+/// In order of priority this is the override for the user, the local or
+/// remote redirect uri template from the config, or the first redirect uri
+/// of the application secret.
+fn get_redirect_uri<USER: EasyString>(
+    app_secret: &oauth2::ApplicationSecret,
+    user: Option<USER>,
+) -> Result<String> {
+    let user: String = match user {
+        Some(user) => user.into(),
+        None => "unknown".to_string(),
+    };
+    let conf = &UPLOADER_CONF.auth;
+    let template = if let Some(uri) = conf.user_redirect_uris.get(&user) {
+        uri.clone()
+    } else if crate::CONF.google.local_auth_redirect {
+        conf.local_redirect_uri.clone()
+    } else if let Some(uri) = &conf.redirect_uri {
+        uri.clone()
+    } else {
+        app_secret
+            .redirect_uris
+            .first()
+            .cloned()
+            .ok_or(AuthError::NoRedirectUri)?
+    };
+    let vars: HashMap<String, String> = HashMap::from([("user".to_string(), user)]);
+    let redirect_uri = strfmt::strfmt(&template, &vars).map_err(AuthError::FormatRedirectUri)?;
+    trace!("redirect uri: {}", redirect_uri);
+    Ok(redirect_uri)
+}
+
+/// Checks the redirect uris of all active users of the installed flow
+/// against the client secret.
+///
+/// This runs at startup, so a wrong uri stops the uploader right away
+/// instead of when a user has to authenticate.
+#[instrument(skip(db))]
+pub(crate) async fn validate_redirect_uris(db: &DatabaseConnection) -> crate::prelude::Result<()> {
+    let users = Users::find()
+        .filter(UsersColumn::Active.eq(true))
+        .all(db)
+        .await?;
+    let users: Vec<&str> = users
+        .iter()
+        .map(|user| user.youtube_id.as_str())
+        .filter(|user| matches!(get_auth_method(user), AuthMethod::Installed))
+        .collect();
+    if users.is_empty() {
+        return Ok(());
+    }
+    let app_secret =
+        read_application_secret(&super::get_client_secret_path()?, AuthMethod::Installed).await?;
+    for user in users {
+        let redirect_uri = get_redirect_uri(&app_secret, Some(user))?;
+        validate_redirect_uri(&redirect_uri, &app_secret.redirect_uris)?;
+    }
+    Ok(())
+}
+
+/// Checks that the redirect uri is one of the redirect uris of the application secret.
+///
+/// Google allows any port and path for loopback redirects of installed apps,
+/// so a loopback uri is accepted if the secret contains any loopback uri.
+fn validate_redirect_uri(redirect_uri: &str, allowed: &[String]) -> Result<()> {
+    if allowed.iter().any(|uri| uri == redirect_uri) {
+        return Ok(());
+    }
+    if is_loopback_uri(redirect_uri) && allowed.iter().any(|uri| is_loopback_uri(uri)) {
+        return Ok(());
+    }
+    error!(
+        "redirect uri '{}' is not one of the redirect uris of the client secret: {:?}",
+        redirect_uri, allowed
+    );
+    Err(AuthError::RedirectUriNotAllowed(redirect_uri.to_string()))
+}
+
+fn is_loopback_uri(uri: &str) -> bool {
+    reqwest::Url::parse(uri).is_ok_and(|uri| {
+        uri.scheme() == "http"
+            && matches!(uri.host_str(), Some("localhost" | "127.0.0.1" | "[::1]"))
+    })
 }
 
 async fn get_and_validate_persistent_path<TEMPLATE: EasyString, USER: EasyString>(
@@ -95,7 +313,58 @@ async fn get_and_validate_persistent_path<TEMPLATE: EasyString, USER: EasyString
     Ok(persistent_path.to_path_buf())
 }
 
-fn get_persistent_path<TEMPLATE: EasyString, USER: EasyString>(
+/// Removes the cached token of the user, so the next authentication starts from scratch.
+///
+/// This removes the tokens from the file and the database, so it also
+/// works after switching the token storage.
+pub(crate) async fn remove_cached_token(
+    db: &DatabaseConnection,
+    user: &str,
+) -> crate::prelude::Result<()> {
+    store::delete_oauth_tokens(db, user).await?;
+    let persistent_path = get_persistent_path(&crate::CONF.google.path_auth_cache, Some(user))?;
+    match fs::remove_file(&persistent_path).await {
+        Ok(()) => {
+            info!("removed cached token of user {}", user);
+            Ok(())
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(AuthError::RemoveTokenCache(e).into()),
+    }
+}
+
+/// Revokes the refresh token of the user with google, which also ends all
+/// access tokens that came from it.
+///
+/// Returns `false` if there is no token, or google did not know it anymore.
+#[instrument(skip(db))]
+pub(crate) async fn revoke_cached_token(
+    db: &DatabaseConnection,
+    user: &str,
+) -> crate::prelude::Result<bool> {
+    let (Some(refresh_token), _) = super::token_health::find_refresh_token(db, user).await? else {
+        return Ok(false);
+    };
+    let response = reqwest::Client::new()
+        .post(GOOGLE_REVOKE_URL)
+        .form(&[("token", refresh_token.as_str())])
+        .send()
+        .await
+        .map_err(AuthError::RevokeToken)?;
+    if response.status().is_success() {
+        info!("revoked token of user {}", user);
+        return Ok(true);
+    }
+    let status = response.status();
+    let text = response.text().await.map_err(AuthError::RevokeToken)?;
+    if text.contains("invalid_token") {
+        warn!("token of user {} was already invalid", user);
+        return Ok(false);
+    }
+    Err(AuthError::RevokeRejected(format!("{}: {}", status, text)).into())
+}
+
+pub(super) fn get_persistent_path<TEMPLATE: EasyString, USER: EasyString>(
     persistent_path_template: TEMPLATE,
     user: Option<USER>,
 ) -> Result<String> {
@@ -108,3 +377,26 @@ fn get_persistent_path<TEMPLATE: EasyString, USER: EasyString>(
         .map_err(PersistentPathError::ReplaceUser)?;
     Ok(persistent_path)
 }
+
+#[cfg(test)]
+mod test {
+    use super::validate_redirect_uri;
+
+    #[test]
+    fn test_validate_redirect_uri() {
+        let allowed = vec![
+            "https://example.com/googleapi/auth".to_string(),
+            "http://localhost".to_string(),
+        ];
+        assert!(validate_redirect_uri("https://example.com/googleapi/auth", &allowed).is_ok());
+        assert!(validate_redirect_uri("https://example.com/other", &allowed).is_err());
+        assert!(validate_redirect_uri("http://localhost:8080/googleapi/auth", &allowed).is_ok());
+        assert!(validate_redirect_uri("http://127.0.0.1:8080/auth", &allowed).is_ok());
+    }
+
+    #[test]
+    fn test_validate_redirect_uri_without_loopback() {
+        let allowed = vec!["https://example.com/googleapi/auth".to_string()];
+        assert!(validate_redirect_uri("http://localhost:8080/googleapi/auth", &allowed).is_err());
+    }
+}
@@ -0,0 +1,140 @@
+use crate::config::AuthCodeProviderKind;
+use crate::control::CONTROL;
+use crate::errors::AuthCodeError;
+use crate::prelude::*;
+use crate::{CONF, UPLOADER_CONF};
+use std::fmt::Debug;
+use std::future::Future;
+use std::pin::Pin;
+use std::time::{Duration, SystemTime};
+use tokio_util::sync::CancellationToken;
+
+mod file;
+mod http;
+mod notifier;
+mod stdin;
+
+type Result<T> = std::result::Result<T, AuthCodeError>;
+
+/// Something that can get the auth code the user got after authenticating.
+///
+/// Implementations do not need to handle timeouts or cancellation, the
+/// future is just dropped in that case (see [`get_auth_code`]).
+pub(crate) trait AuthCodeProvider: Debug + Send + Sync {
+    fn get_code<'a>(
+        &'a self,
+        user: &'a str,
+    ) -> Pin<Box<dyn Future<Output = Result<String>> + Send + 'a>>;
+}
+
+/// Gets the provider from the config.
+///
+/// If none is configured, the http api is used when it runs, then the auth
+/// code file if `use_file_auth_response` is set and stdin otherwise.
+fn get_provider(asked_at: SystemTime) -> Box<dyn AuthCodeProvider> {
+    let kind = UPLOADER_CONF.auth.code_provider.unwrap_or_else(|| {
+        if CONTROL.is_http_enabled() {
+            AuthCodeProviderKind::Http
+        } else if CONF.google.use_file_auth_response {
+            AuthCodeProviderKind::File
+        } else {
+            AuthCodeProviderKind::Stdin
+        }
+    });
+    trace!("using auth code provider: {:?}", kind);
+    match kind {
+        AuthCodeProviderKind::Stdin => Box::new(stdin::StdinProvider),
+        AuthCodeProviderKind::File => Box::new(file::FileProvider::new(
+            CONF.google.path_auth_code.clone(),
+            asked_at,
+        )),
+        AuthCodeProviderKind::Http => Box::new(http::HttpProvider),
+        AuthCodeProviderKind::Notifier => Box::new(notifier::NotifierProvider::new(
+            UPLOADER_CONF.auth.notifier_reply_url.clone(),
+        )),
+    }
+}
+
+/// Gets the auth code for the user from the configured provider.
+///
+/// `asked_at` is when the user was asked for the code, anything older is stale.
+#[tracing::instrument]
+pub(crate) async fn get_auth_code(user: &str, asked_at: SystemTime) -> Result<String> {
+    let provider = get_provider(asked_at);
+    with_timeout_and_cancellation(user, provider.get_code(user)).await
+}
+
+/// Waits for the code from the future, until the configured timeout or
+/// until the authentication of the user is cancelled through the http api.
+pub(crate) async fn with_timeout_and_cancellation(
+    user: &str,
+    code: impl Future<Output = Result<String>>,
+) -> Result<String> {
+    let timeout = Duration::from_secs(UPLOADER_CONF.auth.code_timeout_seconds);
+    let cancellation = CONTROL.register_auth_cancellation(user);
+    let result = wait_for_code(code, timeout, cancellation).await;
+    CONTROL.remove_auth_cancellation(user);
+    result
+}
+
+async fn wait_for_code(
+    code: impl Future<Output = Result<String>>,
+    timeout: Duration,
+    cancellation: CancellationToken,
+) -> Result<String> {
+    let result = tokio::select! {
+        code = code => code,
+        _ = tokio::time::sleep(timeout) => Err(AuthCodeError::Timeout(timeout)),
+        _ = cancellation.cancelled() => Err(AuthCodeError::Cancelled),
+    };
+    let code = result?.trim().to_string();
+    if code.is_empty() {
+        return Err(AuthCodeError::EmptyCode);
+    }
+    Ok(code)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_millis(50);
+
+    #[tokio::test]
+    async fn test_wait_for_code() {
+        let code = wait_for_code(
+            async { Ok(" abc\n".to_string()) },
+            TIMEOUT,
+            CancellationToken::new(),
+        )
+        .await;
+        assert_eq!("abc", code.unwrap());
+
+        let code = wait_for_code(
+            async { Ok(" ".to_string()) },
+            TIMEOUT,
+            CancellationToken::new(),
+        )
+        .await;
+        assert!(matches!(code, Err(AuthCodeError::EmptyCode)));
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_code_timeout() {
+        let code = wait_for_code(std::future::pending(), TIMEOUT, CancellationToken::new()).await;
+        assert!(matches!(code, Err(AuthCodeError::Timeout(_))));
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_code_cancelled() {
+        let cancellation = CancellationToken::new();
+        cancellation.cancel();
+        let code = wait_for_code(
+            std::future::pending(),
+            Duration::from_secs(60),
+            cancellation,
+        )
+        .await;
+        assert!(matches!(code, Err(AuthCodeError::Cancelled)));
+    }
+}
@@ -0,0 +1,163 @@
+use super::{AuthCodeProvider, Result};
+use crate::errors::AuthCodeError;
+use crate::prelude::*;
+use notify::{RecursiveMode, Watcher};
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::time::SystemTime;
+use tokio::sync::mpsc;
+
+/// Waits for the code to be written to a file.
+///
+/// The folder of the file is watched (with inotify on linux), so the code is
+/// picked up as soon as the file is written.
+#[derive(Debug)]
+pub(super) struct FileProvider {
+    path: PathBuf,
+    /// A file from before this is left over from an earlier authentication
+    asked_at: SystemTime,
+}
+
+impl FileProvider {
+    pub(super) fn new(path: impl Into<PathBuf>, asked_at: SystemTime) -> Self {
+        Self {
+            path: path.into(),
+            asked_at,
+        }
+    }
+}
+
+impl AuthCodeProvider for FileProvider {
+    fn get_code<'a>(
+        &'a self,
+        _user: &'a str,
+    ) -> Pin<Box<dyn Future<Output = Result<String>> + Send + 'a>> {
+        Box::pin(wait_for_code(&self.path, self.asked_at))
+    }
+}
+
+#[tracing::instrument]
+async fn wait_for_code(path: &Path, asked_at: SystemTime) -> Result<String> {
+    let folder = path
+        .parent()
+        .filter(|folder| !folder.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |event| {
+        // the receiver is gone once we got the code, so errors can be ignored
+        let _ = sender.send(event);
+    })
+    .map_err(AuthCodeError::Watch)?;
+    watcher
+        .watch(folder, RecursiveMode::NonRecursive)
+        .map_err(AuthCodeError::Watch)?;
+
+    // the watch only sees changes from now on, so the code might already be there
+    if let Some(code) = read_existing_code(path, asked_at)? {
+        return Ok(code);
+    }
+
+    let message = format!("Waiting for auth code in file: {}", path.display());
+    println!("{}", message);
+    info!(message);
+    while let Some(event) = receiver.recv().await {
+        let event = event.map_err(AuthCodeError::Watch)?;
+        if !event
+            .paths
+            .iter()
+            .any(|p| p.ends_with(path.file_name().unwrap_or_default()))
+        {
+            continue;
+        }
+        if let Some(code) = read_code(path) {
+            return Ok(code);
+        }
+    }
+    Err(AuthCodeError::Dropped)
+}
+
+/// Reads the code if the file was written after the user was asked for it.
+/// An older file is removed, its code is from an earlier authentication.
+fn read_existing_code(path: &Path, asked_at: SystemTime) -> Result<Option<String>> {
+    let modified = match std::fs::metadata(path).and_then(|metadata| metadata.modified()) {
+        Ok(modified) => modified,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(AuthCodeError::ReadFile(e)),
+    };
+    if modified >= asked_at {
+        return Ok(read_code(path));
+    }
+    debug!("removing old auth code file: {}", path.display());
+    match std::fs::remove_file(path) {
+        Ok(()) => Ok(None),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => {
+            error!("Error removing file: {}", e);
+            Err(AuthCodeError::RemoveFile(e))
+        }
+    }
+}
+
+/// Reads the first line of the file, if it exists and has one
+fn read_code(path: &Path) -> Option<String> {
+    let content = std::fs::read_to_string(path).ok()?;
+    match content.lines().next() {
+        Some(line) if !line.trim().is_empty() => Some(line.to_string()),
+        _ => {
+            trace!("No code found in file yet");
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    fn test_folder(name: &str) -> PathBuf {
+        let folder = std::env::temp_dir().join(format!(
+            "twba-uploader-auth-code-{}-{}",
+            name,
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&folder).unwrap();
+        folder
+    }
+
+    #[tokio::test]
+    async fn test_code_written_while_waiting() {
+        let path = test_folder("waiting").join("code.txt");
+        let asked_at = SystemTime::now();
+        let writer = {
+            let path = path.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                std::fs::write(path, "abc\n").unwrap();
+            })
+        };
+        let code = tokio::time::timeout(Duration::from_secs(5), wait_for_code(&path, asked_at))
+            .await
+            .expect("the code was not picked up");
+        writer.await.unwrap();
+        assert_eq!("abc", code.unwrap());
+    }
+
+    #[test]
+    fn test_existing_code() {
+        let path = test_folder("existing").join("code.txt");
+        let before = SystemTime::now() - Duration::from_secs(60);
+        std::fs::write(&path, "abc\n").unwrap();
+        assert_eq!(
+            Some("abc".to_string()),
+            read_existing_code(&path, before).unwrap()
+        );
+
+        let after = SystemTime::now() + Duration::from_secs(60);
+        assert_eq!(None, read_existing_code(&path, after).unwrap());
+        assert!(!path.exists(), "the old code file should be removed");
+        assert_eq!(None, read_existing_code(&path, after).unwrap());
+    }
+}
@@ -0,0 +1,67 @@
+use super::{AuthCodeProvider, Result};
+use crate::control::CONTROL;
+use crate::errors::AuthCodeError;
+use crate::prelude::*;
+use std::future::Future;
+use std::pin::Pin;
+
+/// Waits for the code to be posted to the http api
+#[derive(Debug)]
+pub(super) struct HttpProvider;
+
+impl AuthCodeProvider for HttpProvider {
+    fn get_code<'a>(
+        &'a self,
+        user: &'a str,
+    ) -> Pin<Box<dyn Future<Output = Result<String>> + Send + 'a>> {
+        Box::pin(wait_for_code(user))
+    }
+}
+
+/// Removes the pending request when the wait ends, even if it is cancelled
+struct PendingGuard<'a>(&'a str);
+
+impl Drop for PendingGuard<'_> {
+    fn drop(&mut self) {
+        CONTROL.remove_pending_auth_code(self.0);
+    }
+}
+
+async fn wait_for_code(user: &str) -> Result<String> {
+    if !CONTROL.is_http_enabled() {
+        error!("the http api is not running, can not receive the auth code with it");
+        return Err(AuthCodeError::HttpApiDisabled);
+    }
+    let receiver = CONTROL.wait_for_auth_code(user);
+    let _guard = PendingGuard(user);
+    let message = format!(
+        "Waiting for the auth code for {} to be posted to /auth/{}/code",
+        user, user
+    );
+    println!("{}", message);
+    info!(message);
+    receiver.await.map_err(|_| AuthCodeError::Dropped)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_wait_for_code() {
+        CONTROL.set_http_enabled(true);
+        let code = tokio::spawn(wait_for_code("http-provider-test"));
+        let mut submitted = false;
+        for _ in 0..50 {
+            if CONTROL.submit_auth_code("http-provider-test", "abc".to_string()) {
+                submitted = true;
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(submitted, "nothing waited for the code");
+        assert_eq!("abc", code.await.unwrap().unwrap());
+        assert!(!CONTROL.submit_auth_code("http-provider-test", "abc".to_string()));
+    }
+}
@@ -0,0 +1,201 @@
+//! Gets the code as a reply to a notification.
+//!
+//! The notifier has to offer the replies at `notifier_reply_url`:
+//!
+//! - `GET {notifier_reply_url}?key={user}` is polled every
+//!   `notifier_poll_interval_seconds`, `user` is the channel id.
+//! - `204 No Content` means there is no reply yet.
+//! - `200 OK` has the reply, the code, as the plain text body.
+//! - Any other status is an error. Errors are retried a few times in a row
+//!   in case the notifier is restarting, a client error (4xx) stops right
+//!   away, since retrying it will not help.
+use super::{AuthCodeProvider, Result};
+use crate::errors::AuthCodeError;
+use crate::notification::send_notification;
+use crate::prelude::*;
+use crate::UPLOADER_CONF;
+use reqwest::StatusCode;
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Duration;
+
+/// How many polls in a row may fail before giving up
+const MAX_FAILED_POLLS: u32 = 5;
+
+/// Asks for the code through the notifier and waits for the reply
+#[derive(Debug)]
+pub(super) struct NotifierProvider {
+    reply_url: Option<String>,
+}
+
+impl NotifierProvider {
+    pub(super) fn new(reply_url: Option<String>) -> Self {
+        Self { reply_url }
+    }
+}
+
+impl AuthCodeProvider for NotifierProvider {
+    fn get_code<'a>(
+        &'a self,
+        user: &'a str,
+    ) -> Pin<Box<dyn Future<Output = Result<String>> + Send + 'a>> {
+        Box::pin(self.wait_for_reply(user))
+    }
+}
+
+/// What to do after a poll
+#[derive(Debug, PartialEq, Eq)]
+enum PollOutcome {
+    Reply,
+    NoReplyYet,
+    Retry,
+    Fail,
+}
+
+fn poll_outcome(status: StatusCode) -> PollOutcome {
+    match status {
+        StatusCode::OK => PollOutcome::Reply,
+        StatusCode::NO_CONTENT => PollOutcome::NoReplyYet,
+        status if status.is_client_error() => PollOutcome::Fail,
+        _ => PollOutcome::Retry,
+    }
+}
+
+impl NotifierProvider {
+    async fn wait_for_reply(&self, user: &str) -> Result<String> {
+        let reply_url = self
+            .reply_url
+            .as_ref()
+            .ok_or(AuthCodeError::NoNotifierReplyUrl)?;
+        send_notification(format!(
+            "Please reply to this message with the auth code for {}",
+            user
+        ))
+        .await;
+
+        let interval = Duration::from_secs(UPLOADER_CONF.auth.notifier_poll_interval_seconds);
+        poll_for_reply(&reqwest::Client::new(), reply_url, user, interval).await
+    }
+}
+
+async fn poll_for_reply(
+    client: &reqwest::Client,
+    reply_url: &str,
+    user: &str,
+    interval: Duration,
+) -> Result<String> {
+    let mut failed_polls = 0;
+    loop {
+        let response = client.get(reply_url).query(&[("key", user)]).send().await;
+        let outcome = match &response {
+            Ok(response) => poll_outcome(response.status()),
+            Err(_) => PollOutcome::Retry,
+        };
+        match (outcome, response) {
+            (PollOutcome::Reply, Ok(response)) => {
+                let code = response.text().await.map_err(AuthCodeError::Notifier)?;
+                info!("got auth code for {} through the notifier", user);
+                return Ok(code);
+            }
+            (PollOutcome::NoReplyYet, _) => {
+                failed_polls = 0;
+                trace!(
+                    "no reply from the notifier yet, checking again in {:?}",
+                    interval
+                );
+            }
+            (PollOutcome::Fail, Ok(response)) => {
+                return Err(AuthCodeError::NotifierStatus(response.status()));
+            }
+            (_, response) => {
+                failed_polls += 1;
+                let error = match response {
+                    Ok(response) => AuthCodeError::NotifierStatus(response.status()),
+                    Err(e) => AuthCodeError::Notifier(e),
+                };
+                if failed_polls >= MAX_FAILED_POLLS {
+                    error!(
+                        "polling the notifier failed {} times: {}",
+                        failed_polls, error
+                    );
+                    return Err(error);
+                }
+                warn!(
+                    "polling the notifier failed ({}/{}): {}",
+                    failed_polls, MAX_FAILED_POLLS, error
+                );
+            }
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use axum::extract::State;
+    use axum::routing::get;
+    use axum::Router;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_poll_outcome() {
+        assert_eq!(PollOutcome::Reply, poll_outcome(StatusCode::OK));
+        assert_eq!(
+            PollOutcome::NoReplyYet,
+            poll_outcome(StatusCode::NO_CONTENT)
+        );
+        assert_eq!(PollOutcome::Fail, poll_outcome(StatusCode::NOT_FOUND));
+        assert_eq!(PollOutcome::Retry, poll_outcome(StatusCode::BAD_GATEWAY));
+    }
+
+    /// Serves the responses in order, the last one repeats
+    async fn serve(responses: Vec<(StatusCode, &'static str)>) -> (String, Arc<AtomicU32>) {
+        let calls = Arc::new(AtomicU32::new(0));
+        let state = (Arc::new(responses), calls.clone());
+        let app = Router::new()
+            .route(
+                "/reply",
+                get(
+                    |State((responses, calls)): State<(
+                        Arc<Vec<(StatusCode, &'static str)>>,
+                        Arc<AtomicU32>,
+                    )>| async move {
+                        let call = calls.fetch_add(1, Ordering::SeqCst) as usize;
+                        responses[call.min(responses.len() - 1)]
+                    },
+                ),
+            )
+            .with_state(state);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/reply", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        (url, calls)
+    }
+
+    #[tokio::test]
+    async fn test_poll_for_reply() {
+        let (url, _) = serve(vec![
+            (StatusCode::NO_CONTENT, ""),
+            (StatusCode::BAD_GATEWAY, ""),
+            (StatusCode::OK, "abc"),
+        ])
+        .await;
+        let code = poll_for_reply(&reqwest::Client::new(), &url, "user", Duration::ZERO).await;
+        assert_eq!("abc", code.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_poll_for_reply_gives_up() {
+        let (url, calls) = serve(vec![(StatusCode::BAD_GATEWAY, "")]).await;
+        let code = poll_for_reply(&reqwest::Client::new(), &url, "user", Duration::ZERO).await;
+        assert!(matches!(code, Err(AuthCodeError::NotifierStatus(_))));
+        assert_eq!(MAX_FAILED_POLLS, calls.load(Ordering::SeqCst));
+
+        let (url, calls) = serve(vec![(StatusCode::NOT_FOUND, "")]).await;
+        let code = poll_for_reply(&reqwest::Client::new(), &url, "user", Duration::ZERO).await;
+        assert!(matches!(code, Err(AuthCodeError::NotifierStatus(_))));
+        assert_eq!(1, calls.load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,90 @@
+use super::{AuthCodeProvider, Result};
+use crate::errors::AuthCodeError;
+use crate::prelude::*;
+use lazy_static::lazy_static;
+use std::future::Future;
+use std::io::IsTerminal;
+use std::pin::Pin;
+use tokio::sync::{mpsc, Mutex};
+
+type Lines = mpsc::UnboundedReceiver<std::io::Result<String>>;
+
+lazy_static! {
+    /// The lines of stdin, read by one thread for the whole process.
+    ///
+    /// A blocking read can not be cancelled, so a read per authentication
+    /// would keep running after a timeout and take the line meant for the
+    /// next one.
+    static ref STDIN_LINES: Mutex<Lines> = Mutex::new(spawn_stdin_reader());
+}
+
+fn spawn_stdin_reader() -> Lines {
+    let (sender, receiver) = mpsc::unbounded_channel();
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lines() {
+            if sender.send(line).is_err() {
+                break;
+            }
+        }
+    });
+    receiver
+}
+
+/// Reads the code from stdin. Only works if stdin is a terminal.
+#[derive(Debug)]
+pub(super) struct StdinProvider;
+
+impl AuthCodeProvider for StdinProvider {
+    fn get_code<'a>(
+        &'a self,
+        _user: &'a str,
+    ) -> Pin<Box<dyn Future<Output = Result<String>> + Send + 'a>> {
+        Box::pin(read_code())
+    }
+}
+
+async fn read_code() -> Result<String> {
+    if !std::io::stdin().is_terminal() {
+        error!("stdin is not a terminal, can not read the auth code from it");
+        return Err(AuthCodeError::NoTerminal);
+    }
+    let mut lines = STDIN_LINES.lock().await;
+    println!("Please enter the code provided: ");
+    next_line(&mut lines).await
+}
+
+/// Waits for the next line. Lines that were entered before are dropped,
+/// nobody was asked for them.
+async fn next_line(lines: &mut Lines) -> Result<String> {
+    while lines.try_recv().is_ok() {}
+    match lines.recv().await {
+        Some(line) => line.map_err(AuthCodeError::ReadStdin),
+        None => Err(AuthCodeError::Dropped),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_next_line_skips_old_lines() {
+        let (sender, mut lines) = mpsc::unbounded_channel();
+        sender.send(Ok("old".to_string())).unwrap();
+        let line = tokio::spawn(async move { next_line(&mut lines).await });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        sender.send(Ok("new".to_string())).unwrap();
+        assert_eq!("new", line.await.unwrap().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_next_line_reader_gone() {
+        let (sender, mut lines) = mpsc::unbounded_channel::<std::io::Result<String>>();
+        drop(sender);
+        assert!(matches!(
+            next_line(&mut lines).await,
+            Err(AuthCodeError::Dropped)
+        ));
+    }
+}
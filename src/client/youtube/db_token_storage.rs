@@ -0,0 +1,182 @@
+//! Keeps the oauth tokens in the database, so several hosts can share them.
+use crate::client::youtube::token_storage::{decrypt, encrypt, is_encrypted, StoredToken};
+use crate::errors::{AuthError, TokenStorageError};
+use crate::prelude::*;
+use crate::store;
+use aes_gcm::Aes256Gcm;
+use async_trait::async_trait;
+use base64::Engine;
+use google_youtube3::oauth2::storage::{TokenInfo, TokenStorage};
+use twba_local_db::re_exports::sea_orm::DatabaseConnection;
+
+/// A token storage that keeps the tokens of one channel in the database.
+///
+/// The tokens are encrypted with the same key as the token files, if one is
+/// configured.
+pub(super) struct DatabaseTokenStorage {
+    db: DatabaseConnection,
+    channel_id: String,
+    cipher: Option<Aes256Gcm>,
+}
+
+impl DatabaseTokenStorage {
+    pub(super) fn new(
+        db: DatabaseConnection,
+        channel_id: String,
+        cipher: Option<Aes256Gcm>,
+    ) -> Self {
+        Self {
+            db,
+            channel_id,
+            cipher,
+        }
+    }
+
+    /// Reads all tokens of the channel
+    pub(super) async fn get_all(&self) -> Result<Vec<StoredToken>> {
+        let rows = store::get_oauth_tokens(&self.db, &self.channel_id).await?;
+        let mut tokens = Vec::new();
+        for row in rows {
+            let token =
+                decode(self.cipher.as_ref(), &row.token).map_err(AuthError::TokenStorage)?;
+            tokens.push(StoredToken {
+                scopes: row.scopes.split(' ').map(|s| s.to_string()).collect(),
+                token,
+            });
+        }
+        Ok(tokens)
+    }
+
+    pub(super) async fn set_token(&self, scopes: &[String], token: &TokenInfo) -> Result<()> {
+        let mut scopes = scopes.to_vec();
+        scopes.sort();
+        let token = encode(self.cipher.as_ref(), token).map_err(AuthError::TokenStorage)?;
+        store::set_oauth_token(&self.db, &self.channel_id, &scopes.join(" "), token).await
+    }
+}
+
+/// Turns the token into the value of the row, encrypted if there is a cipher
+fn encode(cipher: Option<&Aes256Gcm>, token: &TokenInfo) -> StdResult<String, TokenStorageError> {
+    match cipher {
+        Some(cipher) => {
+            let json = serde_json::to_vec(token).map_err(TokenStorageError::Parse)?;
+            let encrypted = encrypt(cipher, &json)?;
+            Ok(base64::engine::general_purpose::STANDARD.encode(encrypted))
+        }
+        None => serde_json::to_string(token).map_err(TokenStorageError::Parse),
+    }
+}
+
+/// Reads the value of a row, plaintext rows from before the key was set
+/// are still read
+fn decode(cipher: Option<&Aes256Gcm>, value: &str) -> StdResult<TokenInfo, TokenStorageError> {
+    let json = match base64::engine::general_purpose::STANDARD.decode(value) {
+        Ok(data) if is_encrypted(&data) => {
+            let cipher = cipher.ok_or(TokenStorageError::NoKey)?;
+            decrypt(cipher, &data)?
+        }
+        _ => value.as_bytes().to_vec(),
+    };
+    serde_json::from_slice(&json).map_err(TokenStorageError::Parse)
+}
+
+/// Finds a token that was granted all the scopes, it may have more
+fn find_token(tokens: Vec<StoredToken>, scopes: &[&str]) -> Option<TokenInfo> {
+    tokens
+        .into_iter()
+        .find(|stored| {
+            scopes
+                .iter()
+                .all(|scope| stored.scopes.iter().any(|s| s == scope))
+        })
+        .map(|stored| stored.token)
+}
+
+#[async_trait]
+impl TokenStorage for DatabaseTokenStorage {
+    async fn set(&self, scopes: &[&str], token: TokenInfo) -> anyhow::Result<()> {
+        let scopes: Vec<String> = scopes.iter().map(|s| s.to_string()).collect();
+        self.set_token(&scopes, &token)
+            .await
+            .map_err(|e| anyhow::anyhow!("could not store token: {}", e))
+    }
+
+    async fn get(&self, scopes: &[&str]) -> Option<TokenInfo> {
+        let tokens = match self.get_all().await {
+            Ok(tokens) => tokens,
+            Err(e) => {
+                error!("could not read tokens of {}: {}", self.channel_id, e);
+                return None;
+            }
+        };
+        find_token(tokens, scopes)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use aes_gcm::aead::{KeyInit, OsRng};
+
+    fn token(access_token: &str) -> TokenInfo {
+        TokenInfo {
+            access_token: Some(access_token.to_string()),
+            refresh_token: Some("refresh".to_string()),
+            expires_at: None,
+            id_token: None,
+        }
+    }
+
+    #[test]
+    fn test_encode_plain() {
+        let value = encode(None, &token("a")).unwrap();
+        assert!(value.contains("\"refresh\""));
+        assert_eq!(token("a"), decode(None, &value).unwrap());
+    }
+
+    #[test]
+    fn test_encode_encrypted() {
+        let cipher = Aes256Gcm::new(&Aes256Gcm::generate_key(OsRng));
+        let value = encode(Some(&cipher), &token("a")).unwrap();
+        assert!(!value.contains("refresh"));
+        assert_eq!(token("a"), decode(Some(&cipher), &value).unwrap());
+
+        assert!(matches!(
+            decode(None, &value),
+            Err(TokenStorageError::NoKey)
+        ));
+        let other = Aes256Gcm::new(&Aes256Gcm::generate_key(OsRng));
+        assert!(matches!(
+            decode(Some(&other), &value),
+            Err(TokenStorageError::Decrypt)
+        ));
+    }
+
+    #[test]
+    fn test_decode_legacy_plaintext() {
+        let cipher = Aes256Gcm::new(&Aes256Gcm::generate_key(OsRng));
+        let value = encode(None, &token("a")).unwrap();
+        assert_eq!(token("a"), decode(Some(&cipher), &value).unwrap());
+    }
+
+    #[test]
+    fn test_find_token() {
+        let stored = |access_token: &str, scopes: &[&str]| StoredToken {
+            scopes: scopes.iter().map(|s| s.to_string()).collect(),
+            token: token(access_token),
+        };
+        let tokens = vec![
+            stored("upload", &["upload"]),
+            stored("all", &["full", "readonly", "upload"]),
+        ];
+        assert_eq!(
+            Some(token("upload")),
+            find_token(tokens.clone(), &["upload"])
+        );
+        assert_eq!(
+            Some(token("all")),
+            find_token(tokens.clone(), &["readonly", "upload"])
+        );
+        assert_eq!(None, find_token(tokens, &["delete"]));
+    }
+}
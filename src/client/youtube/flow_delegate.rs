@@ -1,43 +1,54 @@
-use crate::errors::AuthError;
+use crate::client::youtube::auth_code;
+use crate::client::youtube::redirect_listener::RedirectListener;
+use crate::control::{AuthPrompt, CONTROL};
+use crate::errors::AuthCodeError;
+use crate::notification::send_notification;
 use crate::prelude::*;
-use google_youtube3::oauth2::authenticator_delegate::InstalledFlowDelegate;
+use google_youtube3::oauth2::authenticator_delegate::{
+    DeviceAuthResponse, DeviceFlowDelegate, InstalledFlowDelegate,
+};
 use std::{
     fmt::{Debug, Formatter},
     future::Future,
-    path::Path,
     pin::Pin,
+    sync::Arc,
+    time::SystemTime,
 };
-use tracing::instrument;
-use twba_common::notify::NotificationRequest;
+use tokio::sync::Notify;
 
 pub struct CustomFlowDelegate<USER: EasyString> {
     user: Option<USER>,
+    redirect_uri: String,
+    /// Notified as soon as the user has to do something
+    interaction_needed: Arc<Notify>,
 }
 
 impl<USER: EasyString> Debug for CustomFlowDelegate<USER> {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("CustomFlowDelegate")
             .field("user", &self.user)
+            .field("redirect_uri", &self.redirect_uri)
             .finish()
     }
 }
 impl<USER: EasyString> CustomFlowDelegate<USER> {
-    pub(crate) fn new(user: Option<USER>) -> Self {
-        Self { user }
+    pub(crate) fn new(
+        user: Option<USER>,
+        redirect_uri: String,
+        interaction_needed: Arc<Notify>,
+    ) -> Self {
+        Self {
+            user,
+            redirect_uri,
+            interaction_needed,
+        }
     }
 }
 impl<USER: EasyString> InstalledFlowDelegate for CustomFlowDelegate<USER> {
     #[tracing::instrument(skip(self))]
     fn redirect_uri(&self) -> Option<&str> {
-        if !(&crate::CONF.google.local_auth_redirect) {
-            let url = "https://game-omgeeky.de:7443/googleapi/auth";
-            trace!("server redirect uri: {}", url);
-            Some(url)
-        } else {
-            let url = "http://localhost:8080/googleapi/auth";
-            trace!("local redirect uri: {}", url);
-            Some(url)
-        }
+        trace!("redirect uri: {}", self.redirect_uri);
+        Some(&self.redirect_uri)
     }
     fn present_user_url<'a>(
         &'a self,
@@ -47,107 +58,100 @@ impl<USER: EasyString> InstalledFlowDelegate for CustomFlowDelegate<USER> {
         Box::pin(self.present_user_url(url, need_code))
     }
 }
+impl<USER: EasyString> DeviceFlowDelegate for CustomFlowDelegate<USER> {
+    fn present_user_code<'a>(
+        &'a self,
+        device_auth_resp: &'a DeviceAuthResponse,
+    ) -> Pin<Box<dyn Future<Output = ()> + Send + 'a>> {
+        Box::pin(self.present_user_code(device_auth_resp))
+    }
+}
 impl<USER: EasyString> CustomFlowDelegate<USER> {
+    #[tracing::instrument(skip(self, device_auth_resp))]
+    async fn present_user_code(&self, device_auth_resp: &DeviceAuthResponse) {
+        self.interaction_needed.notify_one();
+        let message = format!(
+            "Please open {} and enter the code {} to authenticate for {}.\nThe code is valid until {}\n",
+            device_auth_resp.verification_uri,
+            device_auth_resp.user_code,
+            self.user_name(),
+            device_auth_resp.expires_at
+        );
+        println!("{}", message);
+        info!("{}", message);
+        CONTROL.set_auth_prompt(
+            &self.user_name(),
+            AuthPrompt {
+                url: device_auth_resp.verification_uri.clone(),
+                user_code: Some(device_auth_resp.user_code.clone()),
+            },
+        );
+        send_notification(message).await;
+    }
+
     #[tracing::instrument(skip(self, url, need_code))]
     async fn present_user_url(&self, url: &str, need_code: bool) -> StdResult<String, String> {
+        self.interaction_needed.notify_one();
+        let user = self.user_name();
+        if need_code && crate::CONF.google.local_auth_redirect {
+            return auth_code::with_timeout_and_cancellation(
+                &user,
+                self.get_auth_code_from_redirect(url),
+            )
+            .await
+            .map_err(|e| e.to_string());
+        }
+        let asked_at = SystemTime::now();
         self.print_url(url).await?;
         if need_code {
-            let mut code = String::new();
-            if crate::CONF.google.use_file_auth_response {
-                code = get_auth_code().await.unwrap_or("".to_string());
-            }
-            if code.is_empty() {
-                println!("Please enter the code provided: ");
-                match std::io::stdin().read_line(&mut code) {
-                    Ok(_) => {}
-                    Err(e) => {
-                        error!("Error reading line: {}", e);
-                        return Err("".into());
-                    }
-                }
-            }
-            Ok(code)
+            auth_code::get_auth_code(&user, asked_at)
+                .await
+                .map_err(|e| {
+                    error!("could not get the auth code for {}: {}", user, e);
+                    e.to_string()
+                })
         } else {
             Ok("".to_string())
         }
     }
 
-    async fn print_url(&self, url: &str) -> StdResult<(), String> {
-        let user: String = self
-            .user
+    /// Receives the code with a temporary listener on the local redirect uri
+    async fn get_auth_code_from_redirect(&self, url: &str) -> StdResult<String, AuthCodeError> {
+        let listener = RedirectListener::bind(&self.redirect_uri)
+            .await
+            .map_err(AuthCodeError::Redirect)?;
+        self.print_url(&listener.url_with_state(url))
+            .await
+            .map_err(AuthCodeError::Redirect)?;
+        listener
+            .wait_for_code()
+            .await
+            .map_err(AuthCodeError::Redirect)
+    }
+
+    fn user_name(&self) -> String {
+        self.user
             .clone()
             .map(|x| x.into())
-            .unwrap_or_else(|| "unknown".into());
+            .unwrap_or_else(|| "unknown".into())
+    }
+
+    async fn print_url(&self, url: &str) -> StdResult<(), String> {
+        let user = self.user_name();
         let message = format!(
             "Please open this URL in your browser to authenticate for {}:\n{}\n",
             user, url
         );
         println!("{}", message);
         info!("{}", message);
-        Self::send_notification(message).await;
-        Ok(())
-    }
-
-    async fn send_notification(message: String) {
-        let notifier_url = &crate::CONF.notifier.notifier_url;
-        trace!("sending notification at: {}", notifier_url);
-        let response = reqwest::Client::new()
-            .post(notifier_url)
-            .json(&NotificationRequest { message })
-            .send()
-            .await
-            .map_err(|e| format!("Error sending request: {:?}", e));
-        match response {
-            Ok(_) => {
-                trace!("Notification sent successfully");
-            }
-            Err(e) => {
-                error!("Error sending notification: {}", e);
-            }
-        }
-    }
-}
-#[instrument]
-async fn get_auth_code() -> Result<String> {
-    let code: String;
-
-    let path = Path::new(&crate::CONF.google.path_auth_code);
-    if let Err(e) = std::fs::remove_file(path) {
-        if e.kind() != std::io::ErrorKind::NotFound {
-            println!("Error removing file: {}", e);
-            error!("Error removing file: {}", e);
-            return Err(AuthError::RemoveAuthCodeFile(e).into());
-        }
-    }
-    let message = format!("Waiting for auth code in file: {}", path.display());
-    println!("{}", message);
-    info!(message);
-    loop {
-        let res = std::fs::read_to_string(path);
-        if let Ok(content) = res {
-            let line = content.lines().next();
-            let line = match line {
-                Some(s) => s.to_string(),
-                None => {
-                    let message = "No code found in file";
-                    println!("{}", message);
-                    info!(message);
-                    continue;
-                }
-            };
-            code = line;
-            break;
-        }
-
-        println!(
-            "sleeping for {} second before trying again",
-            crate::CONF.google.auth_file_read_timeout
+        CONTROL.set_auth_prompt(
+            &user,
+            AuthPrompt {
+                url: url.to_string(),
+                user_code: None,
+            },
         );
-        tokio::time::sleep(tokio::time::Duration::from_secs(
-            crate::CONF.google.auth_file_read_timeout,
-        ))
-        .await;
+        send_notification(message).await;
+        Ok(())
     }
-
-    Ok(code)
 }
@@ -0,0 +1,197 @@
+use crate::prelude::*;
+use crate::UPLOADER_CONF;
+use axum::extract::{Query, State};
+use axum::response::Html;
+use axum::routing::get;
+use axum::Router;
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use reqwest::Url;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::net::TcpListener;
+use tokio::sync::oneshot;
+
+const SUCCESS_PAGE: &str = "<html><body><h1>Authentication successful</h1>\
+    <p>The uploader got the code, you can close this window now.</p></body></html>";
+
+type CodeSender = Arc<Mutex<Option<oneshot::Sender<String>>>>;
+
+#[derive(Debug, Clone)]
+struct ListenerState {
+    expected_state: String,
+    sender: CodeSender,
+}
+
+/// A temporary http listener that receives the OAuth redirect.
+#[derive(Debug)]
+pub(super) struct RedirectListener {
+    listener: TcpListener,
+    path: String,
+    state: String,
+}
+
+/// Creates a random value for the OAuth `state` parameter
+fn random_state() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect()
+}
+
+impl RedirectListener {
+    /// Starts listening on the address of the redirect uri (or the configured
+    /// listen address), so the listener is ready before the user opens the url.
+    pub(super) async fn bind(redirect_uri: &str) -> StdResult<Self, String> {
+        let uri = Url::parse(redirect_uri)
+            .map_err(|e| format!("invalid redirect uri '{}': {}", redirect_uri, e))?;
+        let address = match &UPLOADER_CONF.auth.local_redirect_listen_address {
+            Some(address) => address.clone(),
+            None => format!(
+                "{}:{}",
+                uri.host_str().unwrap_or("localhost"),
+                uri.port_or_known_default().unwrap_or(80)
+            ),
+        };
+        trace!("starting redirect listener on: {}", address);
+        let listener = TcpListener::bind(&address)
+            .await
+            .map_err(|e| format!("could not listen on {}: {}", address, e))?;
+        Ok(Self {
+            listener,
+            path: uri.path().to_string(),
+            state: random_state(),
+        })
+    }
+
+    /// Adds the `state` parameter that is checked on the redirect to the auth url
+    pub(super) fn url_with_state(&self, url: &str) -> String {
+        format!("{}&state={}", url, self.state)
+    }
+
+    /// Waits for the redirect and returns the code from it
+    pub(super) async fn wait_for_code(self) -> StdResult<String, String> {
+        let (sender, receiver) = oneshot::channel();
+        let sender: CodeSender = Arc::new(Mutex::new(Some(sender)));
+        let state = ListenerState {
+            expected_state: self.state,
+            sender,
+        };
+        let app = Router::new()
+            .route(&self.path, get(redirect))
+            .with_state(state);
+
+        let (shutdown, shutdown_receiver) = oneshot::channel::<()>();
+        let server = axum::serve(self.listener, app).with_graceful_shutdown(async {
+            shutdown_receiver.await.ok();
+        });
+        let server = tokio::spawn(async move { server.await });
+        let code = receiver
+            .await
+            .map_err(|_| "redirect listener stopped without a code".to_string());
+        shutdown.send(()).ok();
+        match server.await {
+            Ok(Ok(())) => trace!("redirect listener stopped"),
+            Ok(Err(e)) => warn!("redirect listener failed: {}", e),
+            Err(e) => warn!("redirect listener did not shut down cleanly: {}", e),
+        }
+        code
+    }
+}
+
+async fn redirect(
+    State(state): State<ListenerState>,
+    Query(params): Query<HashMap<String, String>>,
+) -> StdResult<Html<&'static str>, (axum::http::StatusCode, String)> {
+    let bad_request = |message: String| {
+        warn!("{}", message);
+        (axum::http::StatusCode::BAD_REQUEST, message)
+    };
+    let code = code_from_redirect(&params, &state.expected_state).map_err(bad_request)?;
+
+    let sender = state
+        .sender
+        .lock()
+        .expect("redirect sender lock poisoned")
+        .take();
+    match sender {
+        Some(sender) => {
+            info!("got auth code from redirect");
+            sender.send(code).ok();
+            Ok(Html(SUCCESS_PAGE))
+        }
+        None => Err(bad_request("the code was already received".to_string())),
+    }
+}
+
+/// Gets the code from the query of the redirect, after checking the state
+fn code_from_redirect(
+    params: &HashMap<String, String>,
+    expected_state: &str,
+) -> StdResult<String, String> {
+    if let Some(error) = params.get("error") {
+        return Err(format!("authentication failed: {}", error));
+    }
+    if params.get("state").map(String::as_str) != Some(expected_state) {
+        return Err("the state parameter does not match".to_string());
+    }
+    params
+        .get("code")
+        .cloned()
+        .ok_or_else(|| "no code in the redirect".to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn params(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_code_from_redirect() {
+        assert_eq!(
+            Ok("abc".to_string()),
+            code_from_redirect(&params(&[("code", "abc"), ("state", "s")]), "s")
+        );
+        assert!(code_from_redirect(&params(&[("code", "abc"), ("state", "x")]), "s").is_err());
+        assert!(code_from_redirect(&params(&[("code", "abc")]), "s").is_err());
+        assert!(code_from_redirect(&params(&[("state", "s")]), "s").is_err());
+        assert!(
+            code_from_redirect(&params(&[("error", "access_denied"), ("state", "s")]), "s")
+                .is_err()
+        );
+    }
+
+    async fn test_listener() -> (RedirectListener, String) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let listener = RedirectListener {
+            listener,
+            path: "/auth".to_string(),
+            state: "state".to_string(),
+        };
+        (listener, format!("http://{}/auth", address))
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_code() {
+        let (listener, url) = test_listener().await;
+        let code = tokio::spawn(listener.wait_for_code());
+
+        let wrong_state = reqwest::get(format!("{}?code=abc&state=other", url))
+            .await
+            .unwrap();
+        assert_eq!(reqwest::StatusCode::BAD_REQUEST, wrong_state.status());
+        let response = reqwest::get(format!("{}?code=abc&state=state", url))
+            .await
+            .unwrap();
+        assert!(response.status().is_success());
+        assert_eq!(Ok("abc".to_string()), code.await.unwrap());
+    }
+}
@@ -0,0 +1,179 @@
+use crate::client::youtube::auth;
+use crate::client::youtube::db_token_storage::DatabaseTokenStorage;
+use crate::client::youtube::token_storage::{get_token_cipher, read_token_cache};
+use crate::config::TokenStorageKind;
+use crate::errors::AuthError;
+use crate::prelude::*;
+use crate::UPLOADER_CONF;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::path::Path;
+use tracing::instrument;
+use twba_local_db::re_exports::sea_orm::DatabaseConnection;
+
+/// The result of refreshing the cached token of a user
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum TokenCheck {
+    /// The refresh worked
+    Refreshed {
+        /// Identifies the refresh token without storing it
+        fingerprint: String,
+        /// The remaining lifetime of the refresh token, if google limits it
+        refresh_token_expires_in: Option<i64>,
+    },
+    /// Google does not accept the refresh token anymore
+    Revoked(String),
+    /// There is no refresh token to check
+    Missing(String),
+}
+
+/// One entry of the token cache that yup-oauth2 writes
+#[derive(Debug, Deserialize)]
+struct CachedToken {
+    token: CachedTokenInfo,
+}
+#[derive(Debug, Deserialize)]
+struct CachedTokenInfo {
+    refresh_token: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RefreshResponse {
+    refresh_token_expires_in: Option<i64>,
+}
+#[derive(Debug, Deserialize)]
+struct RefreshErrorResponse {
+    error: String,
+    error_description: Option<String>,
+}
+
+/// Refreshes the cached token of the user, without starting an auth flow.
+///
+/// The refreshed access token is thrown away, the authenticator refreshes
+/// its own token when it needs one.
+#[instrument(skip(db))]
+pub(crate) async fn check_cached_token(db: &DatabaseConnection, user: &str) -> Result<TokenCheck> {
+    let (refresh_token, location) = find_refresh_token(db, user).await?;
+    let Some(refresh_token) = refresh_token else {
+        return Ok(TokenCheck::Missing(format!(
+            "no refresh token in {}",
+            location
+        )));
+    };
+
+    let secret_path = super::get_client_secret_path()?;
+    let app_secret =
+        auth::read_application_secret(&secret_path, auth::get_auth_method(user)).await?;
+
+    trace!("refreshing token of user {}", user);
+    let response = reqwest::Client::new()
+        .post(&app_secret.token_uri)
+        .form(&[
+            ("client_id", app_secret.client_id.as_str()),
+            ("client_secret", app_secret.client_secret.as_str()),
+            ("refresh_token", refresh_token.as_str()),
+            ("grant_type", "refresh_token"),
+        ])
+        .send()
+        .await
+        .map_err(AuthError::RefreshToken)?;
+
+    if response.status().is_success() {
+        let body: RefreshResponse = response.json().await.map_err(AuthError::RefreshToken)?;
+        return Ok(TokenCheck::Refreshed {
+            fingerprint: fingerprint(&refresh_token),
+            refresh_token_expires_in: body.refresh_token_expires_in,
+        });
+    }
+    let status = response.status();
+    let text = response.text().await.map_err(AuthError::RefreshToken)?;
+    match serde_json::from_str::<RefreshErrorResponse>(&text) {
+        Ok(error) if error.error == "invalid_grant" => Ok(TokenCheck::Revoked(
+            error.error_description.unwrap_or(error.error),
+        )),
+        _ => Err(AuthError::RefreshRejected(format!("{}: {}", status, text)).into()),
+    }
+}
+
+/// Finds the refresh token of the user in the configured token storage.
+///
+/// Also returns where it looked, for messages.
+pub(super) async fn find_refresh_token(
+    db: &DatabaseConnection,
+    user: &str,
+) -> Result<(Option<String>, String)> {
+    match UPLOADER_CONF.auth.token_storage {
+        TokenStorageKind::File => {
+            let persistent_path =
+                auth::get_persistent_path(&crate::CONF.google.path_auth_cache, Some(user))?;
+            let token = read_refresh_token(Path::new(&persistent_path)).await?;
+            Ok((token, persistent_path))
+        }
+        TokenStorageKind::Database => {
+            let cipher = get_token_cipher().map_err(AuthError::TokenStorage)?;
+            let storage = DatabaseTokenStorage::new(db.clone(), user.to_string(), cipher);
+            let token = storage
+                .get_all()
+                .await?
+                .into_iter()
+                .find_map(|stored| stored.token.refresh_token);
+            Ok((token, "the database".to_string()))
+        }
+    }
+}
+
+/// Reads the first refresh token from the token cache
+async fn read_refresh_token(path: &Path) -> StdResult<Option<String>, AuthError> {
+    let content = read_token_cache(path).await?;
+    Ok(content.and_then(|content| parse_refresh_token(&content)))
+}
+
+fn parse_refresh_token(content: &[u8]) -> Option<String> {
+    let tokens: Vec<CachedToken> = match serde_json::from_slice(content) {
+        Ok(tokens) => tokens,
+        Err(e) => {
+            warn!("could not parse the token cache: {}", e);
+            return None;
+        }
+    };
+    tokens
+        .into_iter()
+        .find_map(|token| token.token.refresh_token)
+}
+
+/// The first 8 bytes of the SHA-256 of the token as hex.
+///
+/// This stays the same across builds, so it can be compared with older logs.
+fn fingerprint(refresh_token: &str) -> String {
+    Sha256::digest(refresh_token.as_bytes())[..8]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{fingerprint, parse_refresh_token};
+
+    #[test]
+    fn test_fingerprint() {
+        assert_eq!(fingerprint("refresh-token"), "0eb17643d4e92611");
+    }
+
+    #[test]
+    fn test_parse_refresh_token() {
+        let content = r#"[{"scopes":["https://www.googleapis.com/auth/youtube.upload"],
+            "token":{"access_token":"a","refresh_token":"r","expires_at":null,"id_token":null}}]"#;
+        assert_eq!(
+            parse_refresh_token(content.as_bytes()),
+            Some("r".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_refresh_token_missing() {
+        let content = r#"[{"scopes":[],"token":{"access_token":"a","refresh_token":null}}]"#;
+        assert_eq!(parse_refresh_token(content.as_bytes()), None);
+        assert_eq!(parse_refresh_token(b"not json"), None);
+    }
+}
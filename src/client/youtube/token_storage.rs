@@ -0,0 +1,270 @@
+//! Keeps the oauth tokens encrypted on disk.
+//!
+//! The file has the same content as the cache of `persist_tokens_to_disk`,
+//! but encrypted with AES-256-GCM: [`MAGIC`], a 12 byte nonce and the
+//! ciphertext. Plaintext caches are still read, so they keep working until
+//! they are migrated with the `encrypt-tokens` command.
+use crate::errors::{AuthError, TokenStorageError};
+use crate::prelude::*;
+use crate::UPLOADER_CONF;
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use async_trait::async_trait;
+use base64::Engine;
+use google_youtube3::oauth2::storage::{TokenInfo, TokenStorage};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tokio::sync::Mutex;
+
+type Result<T> = StdResult<T, TokenStorageError>;
+
+/// The environment variable that can hold the key (base64 encoded)
+const TOKEN_KEY_ENV: &str = "TWBA_UPLOADER_TOKEN_KEY";
+/// Marks a token cache as encrypted
+const MAGIC: &[u8] = b"TWBAENC1";
+const NONCE_LENGTH: usize = 12;
+
+/// One entry of the token cache, in the format of yup-oauth2
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(super) struct StoredToken {
+    pub scopes: Vec<String>,
+    pub token: TokenInfo,
+}
+
+/// Gets the key for the token cache from the environment or the key file.
+///
+/// Returns `None` if neither is configured, then tokens are stored in plaintext.
+fn get_token_key() -> Result<Option<Key<Aes256Gcm>>> {
+    let encoded = match std::env::var(TOKEN_KEY_ENV) {
+        Ok(key) => key,
+        Err(_) => match &UPLOADER_CONF.auth.token_key_path {
+            Some(path) => {
+                let path = shellexpand::full(path)
+                    .map_err(TokenStorageError::ExpandPath)?
+                    .to_string();
+                std::fs::read_to_string(path).map_err(TokenStorageError::ReadKey)?
+            }
+            None => return Ok(None),
+        },
+    };
+    let key = base64::engine::general_purpose::STANDARD
+        .decode(encoded.trim())
+        .map_err(|e| TokenStorageError::InvalidKey(e.to_string()))?;
+    if key.len() != 32 {
+        return Err(TokenStorageError::InvalidKey(format!(
+            "the key has {} bytes instead of 32",
+            key.len()
+        )));
+    }
+    Ok(Some(*Key::<Aes256Gcm>::from_slice(&key)))
+}
+
+/// The cipher for the configured key, see [`get_token_key`]
+pub(super) fn get_token_cipher() -> Result<Option<Aes256Gcm>> {
+    Ok(get_token_key()?.map(|key| Aes256Gcm::new(&key)))
+}
+
+/// A token storage that encrypts the token cache of one user
+pub(super) struct EncryptedTokenStorage {
+    path: PathBuf,
+    cipher: Aes256Gcm,
+    /// Makes sure two writes do not lose each others tokens
+    lock: Mutex<()>,
+}
+
+impl EncryptedTokenStorage {
+    pub(super) fn new(path: PathBuf, cipher: Aes256Gcm) -> Self {
+        Self {
+            path,
+            cipher,
+            lock: Mutex::new(()),
+        }
+    }
+
+    async fn read_tokens(&self) -> Result<Vec<StoredToken>> {
+        match read_token_file(&self.path, Some(&self.cipher)).await? {
+            Some(content) => serde_json::from_slice(&content).map_err(TokenStorageError::Parse),
+            None => Ok(Vec::new()),
+        }
+    }
+}
+
+#[async_trait]
+impl TokenStorage for EncryptedTokenStorage {
+    async fn set(&self, scopes: &[&str], token: TokenInfo) -> anyhow::Result<()> {
+        let _guard = self.lock.lock().await;
+        let mut tokens = self.read_tokens().await?;
+        let mut scopes: Vec<String> = scopes.iter().map(|s| s.to_string()).collect();
+        scopes.sort();
+        match tokens.iter_mut().find(|stored| stored.scopes == scopes) {
+            Some(stored) => stored.token = token,
+            None => tokens.push(StoredToken { scopes, token }),
+        }
+        let content = serde_json::to_vec(&tokens).map_err(TokenStorageError::Parse)?;
+        write_encrypted(&self.path, &self.cipher, &content).await?;
+        Ok(())
+    }
+
+    async fn get(&self, scopes: &[&str]) -> Option<TokenInfo> {
+        let tokens = match self.read_tokens().await {
+            Ok(tokens) => tokens,
+            Err(e) => {
+                error!("could not read token cache {}: {}", self.path.display(), e);
+                return None;
+            }
+        };
+        tokens
+            .into_iter()
+            .find(|stored| {
+                scopes
+                    .iter()
+                    .all(|scope| stored.scopes.iter().any(|s| s == scope))
+            })
+            .map(|stored| stored.token)
+    }
+}
+
+/// Reads a token cache with the configured key.
+///
+/// Returns `None` if the file does not exist.
+pub(super) async fn read_token_cache(path: &Path) -> Result<Option<Vec<u8>>> {
+    let cipher = get_token_cipher()?;
+    read_token_file(path, cipher.as_ref()).await
+}
+
+/// Reads a token cache and decrypts it if needed.
+///
+/// Returns `None` if the file does not exist.
+async fn read_token_file(path: &Path, cipher: Option<&Aes256Gcm>) -> Result<Option<Vec<u8>>> {
+    let content = match fs::read(path).await {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(TokenStorageError::Read(e)),
+    };
+    if !is_encrypted(&content) {
+        if cipher.is_some() {
+            warn!(
+                "token cache {} is not encrypted yet, run encrypt-tokens to encrypt it",
+                path.display()
+            );
+        }
+        return Ok(Some(content));
+    }
+    let cipher = cipher.ok_or(TokenStorageError::NoKey)?;
+    decrypt(cipher, &content).map(Some)
+}
+
+pub(super) fn is_encrypted(content: &[u8]) -> bool {
+    content.starts_with(MAGIC)
+}
+
+/// Encrypts the content with a fresh nonce
+pub(super) fn encrypt(cipher: &Aes256Gcm, content: &[u8]) -> Result<Vec<u8>> {
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(&nonce, content)
+        .map_err(|_| TokenStorageError::Encrypt)?;
+    let mut data = Vec::with_capacity(MAGIC.len() + NONCE_LENGTH + ciphertext.len());
+    data.extend_from_slice(MAGIC);
+    data.extend_from_slice(&nonce);
+    data.extend_from_slice(&ciphertext);
+    Ok(data)
+}
+
+/// Decrypts content that was encrypted with [`encrypt`]
+pub(super) fn decrypt(cipher: &Aes256Gcm, content: &[u8]) -> Result<Vec<u8>> {
+    let encrypted = content
+        .strip_prefix(MAGIC)
+        .filter(|encrypted| encrypted.len() >= NONCE_LENGTH)
+        .ok_or(TokenStorageError::Decrypt)?;
+    let (nonce, ciphertext) = encrypted.split_at(NONCE_LENGTH);
+    cipher
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| TokenStorageError::Decrypt)
+}
+
+/// Writes the encrypted content to a temporary file and moves it into place,
+/// so a crash never leaves a broken cache behind.
+async fn write_encrypted(path: &Path, cipher: &Aes256Gcm, content: &[u8]) -> Result<()> {
+    let data = encrypt(cipher, content)?;
+    let temp_path = path.with_extension("tmp");
+    fs::write(&temp_path, data)
+        .await
+        .map_err(TokenStorageError::Write)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&temp_path, std::fs::Permissions::from_mode(0o600))
+            .await
+            .map_err(TokenStorageError::Write)?;
+    }
+    fs::rename(&temp_path, path)
+        .await
+        .map_err(TokenStorageError::Write)
+}
+
+/// What happened to a token cache during the migration
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum EncryptOutcome {
+    Encrypted,
+    AlreadyEncrypted,
+    Missing,
+}
+
+/// Encrypts the plaintext token cache of the user in place
+pub(crate) async fn encrypt_token_cache(
+    user: &str,
+    dry_run: bool,
+) -> StdResult<EncryptOutcome, AuthError> {
+    let cipher = get_token_cipher()?.ok_or(TokenStorageError::NoKey)?;
+    let path = super::auth::get_persistent_path(&crate::CONF.google.path_auth_cache, Some(user))?;
+    let path = Path::new(&path);
+    let content = match fs::read(path).await {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(EncryptOutcome::Missing),
+        Err(e) => return Err(TokenStorageError::Read(e).into()),
+    };
+    if is_encrypted(&content) {
+        return Ok(EncryptOutcome::AlreadyEncrypted);
+    }
+    // make sure this really is a token cache before replacing it
+    serde_json::from_slice::<Vec<StoredToken>>(&content).map_err(TokenStorageError::Parse)?;
+    if !dry_run {
+        write_encrypted(path, &cipher, &content).await?;
+        info!("encrypted token cache of user {}", user);
+    }
+    Ok(EncryptOutcome::Encrypted)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_encrypt_and_read() {
+        let key = Aes256Gcm::generate_key(OsRng);
+        let cipher = Aes256Gcm::new(&key);
+        let path = std::env::temp_dir().join(format!("twba-token-test-{}", std::process::id()));
+        write_encrypted(&path, &cipher, b"[]").await.unwrap();
+
+        let raw = fs::read(&path).await.unwrap();
+        assert!(is_encrypted(&raw));
+        let content = read_token_file(&path, Some(&cipher)).await.unwrap();
+        assert_eq!(content.as_deref(), Some(b"[]".as_slice()));
+
+        let other = Aes256Gcm::new(&Aes256Gcm::generate_key(OsRng));
+        assert!(read_token_file(&path, Some(&other)).await.is_err());
+        assert!(read_token_file(&path, None).await.is_err());
+        fs::remove_file(&path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_read_plaintext() {
+        let path = std::env::temp_dir().join(format!("twba-token-plain-{}", std::process::id()));
+        fs::write(&path, b"[]").await.unwrap();
+        let content = read_token_file(&path, None).await.unwrap();
+        assert_eq!(content.as_deref(), Some(b"[]".as_slice()));
+        fs::remove_file(&path).await.unwrap();
+    }
+}
@@ -0,0 +1,530 @@
+use crate::prelude::*;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+/// The environment variable that can point to the uploader specific config file
+const UPLOADER_CONFIG_ENV: &str = "TWBA_UPLOADER_CONFIG";
+/// The path that is used if [`UPLOADER_CONFIG_ENV`] is not set
+const DEFAULT_UPLOADER_CONFIG_PATH: &str = "~/.config/twba/uploader.json";
+
+/// Settings that only the uploader cares about.
+///
+/// The shared settings (db, folders, google secrets, ...) stay in the twba
+/// config ([`crate::CONF`]), this only holds the things that are specific
+/// to uploading. Every field has a default, so a missing file is fine.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct UploaderConf {
+    pub budget: BudgetConf,
+    pub daemon: DaemonConf,
+    pub quota: QuotaConf,
+    pub auth: AuthConf,
+    pub token_health: TokenHealthConf,
+    pub templates: TemplatesConf,
+    pub metadata: MetadataConf,
+    /// Overrides for single users, keyed by their user id
+    pub profiles: HashMap<i32, UserProfile>,
+    /// The ffprobe binary for the part durations, `ffprobe` from the PATH if not set
+    pub ffprobe_path: Option<String>,
+}
+
+/// Limits for a single run of the uploader.
+///
+/// Once any of the limits is reached, no new parts are started. The part
+/// that is currently uploading is always finished.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct BudgetConf {
+    /// The maximum amount of bytes to upload in one run
+    pub max_bytes: Option<u64>,
+    /// The maximum amount of media (in seconds) to upload in one run
+    pub max_media_seconds: Option<u64>,
+    /// The maximum time (in seconds) a run may take before it stops starting new parts
+    pub max_wall_clock_seconds: Option<u64>,
+}
+
+/// Settings for running the uploader as a long running process
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct DaemonConf {
+    /// The address the http api listens on. The api is disabled if this is not set
+    pub http_address: Option<String>,
+    /// The time (in seconds) to wait between two runs
+    pub run_interval_seconds: u64,
+    /// The url the http api can be reached at, for links in notifications.
+    ///
+    /// Defaults to `http://{http_address}`
+    pub public_url: Option<String>,
+    /// The token every endpoint but `/health` and `/ready` requires, either as
+    /// `Authorization: Bearer <token>` or as `?token=<token>` for links. It is
+    /// never put into notifications, so links in them need it added by hand.
+    ///
+    /// Without a token the api only listens on loopback addresses.
+    pub api_token: Option<String>,
+}
+
+impl Default for DaemonConf {
+    fn default() -> Self {
+        Self {
+            http_address: None,
+            run_interval_seconds: 60 * 60,
+            public_url: None,
+            api_token: None,
+        }
+    }
+}
+
+impl DaemonConf {
+    /// The url of the http api for links, if the api is enabled
+    pub fn api_url(&self) -> Option<String> {
+        self.public_url
+            .clone()
+            .or_else(|| self.http_address.as_ref().map(|a| format!("http://{}", a)))
+            .map(|url| url.trim_end_matches('/').to_string())
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct QuotaConf {
+    /// The daily YouTube api quota of the google project
+    pub daily_limit: i64,
+}
+
+impl Default for QuotaConf {
+    fn default() -> Self {
+        Self {
+            daily_limit: 10_000,
+        }
+    }
+}
+
+/// How a user authenticates with google
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthMethod {
+    /// The installed app flow with a redirect (or a pasted code)
+    #[default]
+    Installed,
+    /// The device flow, where the user enters a code on google.com/device.
+    ///
+    /// This needs a client secret of the type "TVs and Limited Input devices"
+    Device,
+}
+
+/// Where the auth code comes from after the user authenticated
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthCodeProviderKind {
+    /// Typed into the terminal
+    Stdin,
+    /// Written to `path_auth_code`
+    File,
+    /// Posted to the http api
+    Http,
+    /// Sent as a reply to the notification
+    Notifier,
+}
+
+/// What the uploader may do on the channel of a user.
+///
+/// Every feature needs its own oauth scope, so users only have to consent
+/// to what they use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum YoutubeFeature {
+    /// Uploading videos
+    Upload,
+    /// Creating playlists and deleting them (for rollbacks)
+    Playlists,
+    /// Deleting uploaded videos (for rollbacks)
+    Delete,
+    /// Reading the channel, this is always needed to verify the channel
+    ReadOnly,
+}
+
+impl std::fmt::Display for YoutubeFeature {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let feature = match self {
+            YoutubeFeature::Upload => "upload",
+            YoutubeFeature::Playlists => "playlists",
+            YoutubeFeature::Delete => "delete",
+            YoutubeFeature::ReadOnly => "read only",
+        };
+        write!(f, "{}", feature)
+    }
+}
+
+/// Where the oauth tokens are kept
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenStorageKind {
+    /// One file per user, from the `path_auth_cache` template
+    #[default]
+    File,
+    /// A table in the twba database, shared by all hosts that use it
+    Database,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AuthConf {
+    /// The auth method for all users that have none set in `user_methods`
+    pub method: AuthMethod,
+    /// Auth methods for single users, keyed by their channel id
+    pub user_methods: HashMap<String, AuthMethod>,
+    /// The features for all users that have none set in `user_features`
+    pub features: Vec<YoutubeFeature>,
+    /// Features for single users, keyed by their channel id
+    pub user_features: HashMap<String, Vec<YoutubeFeature>>,
+    /// Where the oauth tokens are kept
+    pub token_storage: TokenStorageKind,
+    /// A file with the base64 encoded 32 byte key for the token cache.
+    ///
+    /// The key can also be set in `TWBA_UPLOADER_TOKEN_KEY`. Tokens are
+    /// stored in plaintext if neither is set.
+    pub token_key_path: Option<String>,
+    /// The client secret for the device flow, if it differs from the normal one
+    pub device_client_secret_path: Option<String>,
+    /// The redirect uri template for the remote auth flow. `{user}` is replaced
+    /// with the channel id. Defaults to the first redirect uri of the client secret
+    pub redirect_uri: Option<String>,
+    /// The redirect uri template that is used when `local_auth_redirect` is on
+    pub local_redirect_uri: String,
+    /// Redirect uri templates for single users, keyed by their channel id
+    pub user_redirect_uris: HashMap<String, String>,
+    /// The address the temporary listener for the local auth redirect binds to.
+    ///
+    /// Defaults to the host and port of the redirect uri. This needs to be set
+    /// (for example to `0.0.0.0:8080`) when running in a container.
+    pub local_redirect_listen_address: Option<String>,
+    /// Where to get the auth code from. Chosen from the other settings if not set
+    pub code_provider: Option<AuthCodeProviderKind>,
+    /// The time (in seconds) to wait for an auth code before giving up
+    pub code_timeout_seconds: u64,
+    /// The url to fetch replies to notifications from, polled with
+    /// `?key={channel id}`. It answers 204 until there is a reply and 200
+    /// with the reply as the body once there is one
+    pub notifier_reply_url: Option<String>,
+    /// The time (in seconds) between checks for a reply from the notifier
+    pub notifier_poll_interval_seconds: u64,
+    /// The time (in minutes) between reminders that a user has to authenticate again.
+    ///
+    /// The n-th reminder waits for the n-th interval, the last one repeats.
+    pub reminder_intervals_minutes: Vec<u64>,
+}
+
+impl Default for AuthConf {
+    fn default() -> Self {
+        Self {
+            method: AuthMethod::default(),
+            user_methods: HashMap::new(),
+            features: vec![
+                YoutubeFeature::Upload,
+                YoutubeFeature::Playlists,
+                YoutubeFeature::Delete,
+                YoutubeFeature::ReadOnly,
+            ],
+            user_features: HashMap::new(),
+            token_storage: TokenStorageKind::default(),
+            token_key_path: None,
+            device_client_secret_path: None,
+            redirect_uri: None,
+            local_redirect_uri: "http://localhost:8080/googleapi/auth".to_string(),
+            user_redirect_uris: HashMap::new(),
+            local_redirect_listen_address: None,
+            code_provider: None,
+            code_timeout_seconds: 24 * 60 * 60,
+            notifier_reply_url: None,
+            notifier_poll_interval_seconds: 30,
+            reminder_intervals_minutes: vec![60, 4 * 60, 12 * 60, 24 * 60],
+        }
+    }
+}
+
+/// Settings for checking the cached tokens of all users
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct TokenHealthConf {
+    /// The time (in seconds) between checks in the daemon. No checks if not set
+    pub check_interval_seconds: Option<u64>,
+    /// A token counts as expiring if it expires within this many days
+    pub expiring_warning_days: i64,
+    /// The lifetime of refresh tokens, if google limits it.
+    ///
+    /// This is 7 for apps that are in the testing publishing status.
+    pub refresh_token_lifetime_days: Option<i64>,
+}
+
+impl Default for TokenHealthConf {
+    fn default() -> Self {
+        Self {
+            check_interval_seconds: Some(6 * 60 * 60),
+            expiring_warning_days: 2,
+            refresh_token_lifetime_days: None,
+        }
+    }
+}
+
+/// The templates for titles and descriptions.
+///
+/// Templates that are not set use the built in defaults. The descriptions
+/// fall back to `default_description_template` of the twba config first.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct TemplatesConf {
+    pub video_title: Option<String>,
+    pub video_description: Option<String>,
+    pub playlist_title: Option<String>,
+    pub playlist_description: Option<String>,
+}
+
+/// Who can see the uploaded videos and playlists
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Privacy {
+    #[default]
+    Private,
+    Unlisted,
+    Public,
+}
+
+/// The metadata of the uploaded videos
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct MetadataConf {
+    /// The tags every part gets
+    pub tags: Vec<String>,
+    /// Adds the twitch channel name to the tags
+    pub tag_channel_name: bool,
+    /// Adds the game or category of the stream to the tags
+    pub tag_game: bool,
+    /// Adds hashtags of the channel name, the game and `extra_hashtags` to
+    /// the description of a part, unless its template places `hashtags` itself
+    pub hashtags: bool,
+    pub extra_hashtags: Vec<String>,
+    /// The id of the YouTube category
+    pub category: u32,
+    /// The YouTube category id of twitch games, like `"Just Chatting" = 22`.
+    /// Games are matched ignoring case, others get `category`
+    pub game_categories: HashMap<String, u32>,
+    pub privacy: Privacy,
+    pub made_for_kids: bool,
+    /// Variables the templates can use as `{{ vars.name }}`
+    pub vars: HashMap<String, String>,
+    /// Adds the chapters to the description of a part, unless its template
+    /// already places `chapters` itself
+    pub append_chapters: bool,
+}
+
+impl Default for MetadataConf {
+    fn default() -> Self {
+        Self {
+            tags: Vec::new(),
+            tag_channel_name: true,
+            tag_game: true,
+            hashtags: false,
+            extra_hashtags: Vec::new(),
+            category: 22,
+            game_categories: HashMap::new(),
+            privacy: Privacy::default(),
+            made_for_kids: false,
+            vars: HashMap::new(),
+            append_chapters: true,
+        }
+    }
+}
+
+/// Overrides for a single user, everything that is not set stays as configured
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct UserProfile {
+    pub templates: TemplatesConf,
+    pub tags: Option<Vec<String>>,
+    /// Added after the tags
+    pub extra_tags: Vec<String>,
+    pub hashtags: Option<bool>,
+    /// Added after the configured extra hashtags
+    pub extra_hashtags: Vec<String>,
+    pub category: Option<u32>,
+    /// Added to the configured game categories, replacing the same games
+    pub game_categories: HashMap<String, u32>,
+    pub privacy: Option<Privacy>,
+    pub made_for_kids: Option<bool>,
+    /// Added to the configured variables, replacing the ones with the same name
+    pub vars: HashMap<String, String>,
+}
+
+impl UploaderConf {
+    /// The metadata for the videos of the user, with their profile applied
+    pub fn metadata_for(&self, user_id: i32) -> MetadataConf {
+        let mut metadata = self.metadata.clone();
+        let Some(profile) = self.profiles.get(&user_id) else {
+            return metadata;
+        };
+        if let Some(tags) = &profile.tags {
+            metadata.tags = tags.clone();
+        }
+        metadata.tags.extend(profile.extra_tags.clone());
+        metadata.hashtags = profile.hashtags.unwrap_or(metadata.hashtags);
+        metadata
+            .extra_hashtags
+            .extend(profile.extra_hashtags.clone());
+        metadata.category = profile.category.unwrap_or(metadata.category);
+        for (game, category) in &profile.game_categories {
+            metadata
+                .game_categories
+                .retain(|configured, _| !configured.eq_ignore_ascii_case(game));
+            metadata.game_categories.insert(game.clone(), *category);
+        }
+        metadata.privacy = profile.privacy.unwrap_or(metadata.privacy);
+        metadata.made_for_kids = profile.made_for_kids.unwrap_or(metadata.made_for_kids);
+        metadata.vars.extend(profile.vars.clone());
+        metadata
+    }
+}
+
+impl MetadataConf {
+    /// The category of a stream of the game
+    pub fn category_for_game(&self, game: Option<&str>) -> u32 {
+        game.and_then(|game| {
+            self.game_categories
+                .iter()
+                .find(|(configured, _)| configured.eq_ignore_ascii_case(game))
+                .map(|(_, category)| *category)
+        })
+        .unwrap_or(self.category)
+    }
+
+    /// Every category id that can be used, sorted
+    pub fn category_ids(&self) -> Vec<u32> {
+        let mut ids: Vec<u32> = self.game_categories.values().copied().collect();
+        ids.push(self.category);
+        ids.sort_unstable();
+        ids.dedup();
+        ids
+    }
+}
+
+impl AuthConf {
+    /// The features of the user. Read only is always included
+    pub fn features_for(&self, user: &str) -> Vec<YoutubeFeature> {
+        let mut features = self
+            .user_features
+            .get(user)
+            .unwrap_or(&self.features)
+            .clone();
+        if !features.contains(&YoutubeFeature::ReadOnly) {
+            features.push(YoutubeFeature::ReadOnly);
+        }
+        features
+    }
+    pub fn has_feature(&self, user: &str, feature: YoutubeFeature) -> bool {
+        self.features_for(user).contains(&feature)
+    }
+}
+
+pub(crate) fn get_uploader_config() -> UploaderConf {
+    let path = std::env::var(UPLOADER_CONFIG_ENV)
+        .unwrap_or_else(|_| DEFAULT_UPLOADER_CONFIG_PATH.to_string());
+    let path = PathBuf::from(
+        shellexpand::full(&path)
+            .expect("could not expand uploader config path")
+            .to_string(),
+    );
+    if !path.exists() {
+        debug!(
+            "no uploader config found at {}, using defaults",
+            path.display()
+        );
+        return UploaderConf::default();
+    }
+    trace!("reading uploader config from {}", path.display());
+    let content = std::fs::read_to_string(&path).expect("could not read uploader config");
+    serde_json::from_str(&content).expect("could not parse uploader config")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn conf() -> UploaderConf {
+        UploaderConf {
+            metadata: MetadataConf {
+                tags: vec!["base".to_string()],
+                extra_hashtags: vec!["Base".to_string()],
+                category: 22,
+                game_categories: HashMap::from([("Just Chatting".to_string(), 24)]),
+                vars: HashMap::from([
+                    ("a".to_string(), "1".to_string()),
+                    ("b".to_string(), "2".to_string()),
+                ]),
+                ..MetadataConf::default()
+            },
+            ..UploaderConf::default()
+        }
+    }
+
+    #[test]
+    fn test_metadata_for_without_profile() {
+        let metadata = conf().metadata_for(1);
+        assert_eq!(vec!["base".to_string()], metadata.tags);
+        assert_eq!(22, metadata.category);
+        assert_eq!(Privacy::Private, metadata.privacy);
+    }
+
+    #[test]
+    fn test_metadata_for_profile() {
+        let mut conf = conf();
+        conf.profiles.insert(
+            1,
+            UserProfile {
+                extra_tags: vec!["extra".to_string()],
+                extra_hashtags: vec!["Profile".to_string()],
+                category: Some(20),
+                game_categories: HashMap::from([("just chatting".to_string(), 10)]),
+                privacy: Some(Privacy::Public),
+                vars: HashMap::from([("b".to_string(), "3".to_string())]),
+                ..UserProfile::default()
+            },
+        );
+        let metadata = conf.metadata_for(1);
+        assert_eq!(vec!["base".to_string(), "extra".to_string()], metadata.tags);
+        assert_eq!(
+            vec!["Base".to_string(), "Profile".to_string()],
+            metadata.extra_hashtags
+        );
+        assert_eq!(20, metadata.category);
+        assert_eq!(1, metadata.game_categories.len());
+        assert_eq!(10, metadata.category_for_game(Some("Just Chatting")));
+        assert_eq!(Privacy::Public, metadata.privacy);
+        assert!(!metadata.made_for_kids);
+        assert_eq!("1", metadata.vars["a"]);
+        assert_eq!("3", metadata.vars["b"]);
+
+        conf.profiles.get_mut(&1).unwrap().tags = Some(vec!["own".to_string()]);
+        assert_eq!(
+            vec!["own".to_string(), "extra".to_string()],
+            conf.metadata_for(1).tags
+        );
+        assert_eq!(vec!["base".to_string()], conf.metadata_for(2).tags);
+    }
+
+    #[test]
+    fn test_category_for_game() {
+        let metadata = conf().metadata;
+        assert_eq!(24, metadata.category_for_game(Some("Just Chatting")));
+        assert_eq!(24, metadata.category_for_game(Some("JUST CHATTING")));
+        assert_eq!(22, metadata.category_for_game(Some("Chess")));
+        assert_eq!(22, metadata.category_for_game(None));
+    }
+
+    #[test]
+    fn test_category_ids() {
+        let mut metadata = conf().metadata;
+        metadata.game_categories.insert("Chess".to_string(), 22);
+        metadata.game_categories.insert("Music".to_string(), 10);
+        assert_eq!(vec![10, 22, 24], metadata.category_ids());
+    }
+}
@@ -0,0 +1,223 @@
+use crate::prelude::*;
+use lazy_static::lazy_static;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use tokio::sync::{oneshot, Notify};
+use tokio_util::sync::CancellationToken;
+
+lazy_static! {
+    /// The state that is shared between the uploader and the http api
+    pub(crate) static ref CONTROL: ControlState = ControlState::default();
+}
+
+/// The part that is currently being uploaded
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct InFlight {
+    pub video_id: i32,
+    pub video_name: String,
+    pub part: usize,
+    pub part_count: i32,
+    pub started_at: String,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct ControlState {
+    ready: AtomicBool,
+    http_enabled: AtomicBool,
+    in_flight: Mutex<Option<InFlight>>,
+    cancelled: Mutex<HashSet<i32>>,
+    run_trigger: Notify,
+    pending_auth_codes: Mutex<HashMap<String, oneshot::Sender<String>>>,
+    auth_cancellations: Mutex<HashMap<String, CancellationToken>>,
+    auth_prompts: Mutex<HashMap<String, AuthPrompt>>,
+    reauth_requests: Mutex<HashSet<String>>,
+    reauth_trigger: Notify,
+}
+
+/// What a user has to open (and enter) to authenticate
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct AuthPrompt {
+    pub url: String,
+    /// The code to enter on the page, for the device flow
+    pub user_code: Option<String>,
+}
+
+impl ControlState {
+    pub(crate) fn set_ready(&self, ready: bool) {
+        self.ready.store(ready, Ordering::SeqCst);
+    }
+    pub(crate) fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::SeqCst)
+    }
+    pub(crate) fn set_http_enabled(&self, enabled: bool) {
+        self.http_enabled.store(enabled, Ordering::SeqCst);
+    }
+    /// Whether auth codes can be submitted through the http api
+    pub(crate) fn is_http_enabled(&self) -> bool {
+        self.http_enabled.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn set_in_flight(&self, in_flight: Option<InFlight>) {
+        *self.in_flight.lock().expect("in flight lock poisoned") = in_flight;
+    }
+    pub(crate) fn in_flight(&self) -> Option<InFlight> {
+        self.in_flight
+            .lock()
+            .expect("in flight lock poisoned")
+            .clone()
+    }
+
+    /// Marks a video as cancelled.
+    ///
+    /// A cancelled video is not started anymore and if it is currently
+    /// uploading, no new parts are started for it. This lasts until the
+    /// video is retried or the process is restarted.
+    pub(crate) fn cancel(&self, video_id: i32) {
+        self.cancelled
+            .lock()
+            .expect("cancelled lock poisoned")
+            .insert(video_id);
+    }
+    pub(crate) fn uncancel(&self, video_id: i32) {
+        self.cancelled
+            .lock()
+            .expect("cancelled lock poisoned")
+            .remove(&video_id);
+    }
+    pub(crate) fn is_cancelled(&self, video_id: i32) -> bool {
+        self.cancelled
+            .lock()
+            .expect("cancelled lock poisoned")
+            .contains(&video_id)
+    }
+
+    pub(crate) fn trigger_run(&self) {
+        self.run_trigger.notify_one();
+    }
+    pub(crate) async fn wait_for_run_trigger(&self) {
+        self.run_trigger.notified().await;
+    }
+
+    /// Registers a pending authentication for the user and returns the
+    /// receiver that gets the code once it is submitted.
+    pub(crate) fn wait_for_auth_code(&self, user: &str) -> oneshot::Receiver<String> {
+        let (sender, receiver) = oneshot::channel();
+        let previous = self
+            .pending_auth_codes
+            .lock()
+            .expect("auth code lock poisoned")
+            .insert(user.to_string(), sender);
+        if previous.is_some() {
+            warn!("replaced a pending auth code request for user: {}", user);
+        }
+        receiver
+    }
+    /// Hands the code to the pending authentication of the user.
+    ///
+    /// Returns false if nothing is waiting for a code for this user.
+    pub(crate) fn submit_auth_code(&self, user: &str, code: String) -> bool {
+        let sender = self
+            .pending_auth_codes
+            .lock()
+            .expect("auth code lock poisoned")
+            .remove(user);
+        match sender {
+            Some(sender) => sender.send(code).is_ok(),
+            None => false,
+        }
+    }
+    pub(crate) fn remove_pending_auth_code(&self, user: &str) {
+        self.pending_auth_codes
+            .lock()
+            .expect("auth code lock poisoned")
+            .remove(user);
+    }
+    pub(crate) fn pending_auth_users(&self) -> Vec<String> {
+        self.pending_auth_codes
+            .lock()
+            .expect("auth code lock poisoned")
+            .keys()
+            .cloned()
+            .collect()
+    }
+
+    /// Creates the token that cancels waiting for the auth code of the user
+    pub(crate) fn register_auth_cancellation(&self, user: &str) -> CancellationToken {
+        let token = CancellationToken::new();
+        self.auth_cancellations
+            .lock()
+            .expect("auth cancellation lock poisoned")
+            .insert(user.to_string(), token.clone());
+        token
+    }
+    pub(crate) fn remove_auth_cancellation(&self, user: &str) {
+        self.auth_cancellations
+            .lock()
+            .expect("auth cancellation lock poisoned")
+            .remove(user);
+    }
+    /// Cancels waiting for the auth code of the user.
+    ///
+    /// Returns false if nothing is waiting for a code for this user.
+    pub(crate) fn cancel_auth(&self, user: &str) -> bool {
+        let token = self
+            .auth_cancellations
+            .lock()
+            .expect("auth cancellation lock poisoned")
+            .remove(user);
+        match token {
+            Some(token) => {
+                token.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Remembers what the user has to open to authenticate, until the flow ends
+    pub(crate) fn set_auth_prompt(&self, user: &str, prompt: AuthPrompt) {
+        self.auth_prompts
+            .lock()
+            .expect("auth prompt lock poisoned")
+            .insert(user.to_string(), prompt);
+    }
+    pub(crate) fn remove_auth_prompt(&self, user: &str) {
+        self.auth_prompts
+            .lock()
+            .expect("auth prompt lock poisoned")
+            .remove(user);
+    }
+    pub(crate) fn auth_prompt(&self, user: &str) -> Option<AuthPrompt> {
+        self.auth_prompts
+            .lock()
+            .expect("auth prompt lock poisoned")
+            .get(user)
+            .cloned()
+    }
+
+    /// Asks the uploader to authenticate the user again
+    pub(crate) fn request_reauth(&self, user: &str) {
+        self.reauth_requests
+            .lock()
+            .expect("reauth lock poisoned")
+            .insert(user.to_string());
+        self.reauth_trigger.notify_one();
+    }
+    /// Waits for re-auth requests and returns all users that were requested
+    pub(crate) async fn wait_for_reauth_requests(&self) -> Vec<String> {
+        loop {
+            let requests: Vec<String> = self
+                .reauth_requests
+                .lock()
+                .expect("reauth lock poisoned")
+                .drain()
+                .collect();
+            if !requests.is_empty() {
+                return requests;
+            }
+            self.reauth_trigger.notified().await;
+        }
+    }
+}
@@ -23,6 +23,18 @@ pub enum UploaderError {
 
     #[error("Could not find user: {0}")]
     UnknownUser(i32),
+    #[error("Could not find video: {0}")]
+    UnknownVideo(i32),
+    #[error("Could not find user with channel: {0}")]
+    UnknownChannel(String),
+    #[error("There already is an active user for: {0}")]
+    UserExists(String),
+    #[error("The twitch and youtube ids belong to different users: {0}")]
+    UserConflict(String),
+    #[error("Video {0} has status {1}, use --force to roll it back anyway")]
+    RollbackRefused(i32, String),
+    #[error("The token has no access for: {0}")]
+    InsufficientScope(crate::config::YoutubeFeature),
     #[error("Could not find client for user: {0}")]
     NoClient(i32),
     #[error("Could not read part file: {0}")]
@@ -43,10 +55,22 @@ pub enum UploaderError {
     SaveVideoStatus(#[source] twba_local_db::re_exports::sea_orm::DbErr),
     #[error("could not parse date: {0}")]
     ParseDate(#[source] chrono::ParseError),
+    #[error("could not render template: {0}")]
+    RenderTemplate(#[source] minijinja::Error),
     #[error("part count does not match: expected: {0}, got: {1}")]
     PartCountMismatch(usize, usize),
     #[error("no id returned from youtube")]
     NoIdReturned,
+    #[error("the http api needs an api token to listen on {0}, which is not a loopback address")]
+    ApiTokenRequired(String),
+    #[error("error with the http api: {0}")]
+    HttpServer(#[source] std::io::Error),
+    #[error("could not serialize report: {0}")]
+    SerializeReport(#[source] serde_json::Error),
+    #[error("could not serialize the video overrides: {0}")]
+    SerializeOverrides(#[source] serde_json::Error),
+    #[error("could not read confirmation: {0}")]
+    ReadConfirmation(#[source] std::io::Error),
 
     #[error("This error should be unreachable: {0}")]
     Unreachable(String),
@@ -54,16 +78,94 @@ pub enum UploaderError {
 
 #[derive(Debug, thiserror::Error)]
 pub enum AuthError {
+    #[error("Path could not be expanded")]
+    ExpandPath(#[source] LookupError<VarError>),
     #[error("could not read application secret from path: {0}")]
     ReadApplicationSecret(#[source] std::io::Error),
     #[error("could not create auth")]
     CreateAuth(#[source] std::io::Error),
     #[error("could not get access to the requested scopes")]
-    GetAccessToken(#[source] Box<dyn std::error::Error>),
+    GetAccessToken(#[source] Box<dyn std::error::Error + Send + Sync>),
+    #[error("the authentication task failed: {0}")]
+    AuthTask(#[source] tokio::task::JoinError),
     #[error("could not get and validate persistent path: {0}")]
     PersistentPathError(#[from] PersistentPathError),
+    #[error("no redirect uri configured and the client secret has none")]
+    NoRedirectUri,
+    #[error("could not replace user in redirect uri")]
+    FormatRedirectUri(#[source] FmtError),
+    #[error("redirect uri is not allowed by the client secret: {0}")]
+    RedirectUriNotAllowed(String),
+    #[error("could not send the token refresh request: {0}")]
+    RefreshToken(#[source] reqwest::Error),
+    #[error("google rejected the token refresh: {0}")]
+    RefreshRejected(String),
+    #[error("could not send the token revocation: {0}")]
+    RevokeToken(#[source] reqwest::Error),
+    #[error("google rejected the token revocation: {0}")]
+    RevokeRejected(String),
+    #[error("could not remove the token cache: {0}")]
+    RemoveTokenCache(#[source] std::io::Error),
+    #[error("token storage error: {0}")]
+    TokenStorage(#[from] TokenStorageError),
+    #[error("the authenticated account has no youtube channel")]
+    NoChannel,
+    #[error("authenticated for channel {actual} but the user has channel {expected}")]
+    ChannelMismatch { expected: String, actual: String },
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum AuthCodeError {
+    #[error("got no auth code within {0:?}")]
+    Timeout(std::time::Duration),
+    #[error("the authentication was cancelled")]
+    Cancelled,
+    #[error("the auth code was empty")]
+    EmptyCode,
+    #[error("the auth code source went away before a code arrived")]
+    Dropped,
+    #[error("stdin is not a terminal")]
+    NoTerminal,
+    #[error("could not read the auth code from stdin: {0}")]
+    ReadStdin(#[source] std::io::Error),
     #[error("could not remove existing auth code file: {0}")]
-    RemoveAuthCodeFile(#[source] std::io::Error),
+    RemoveFile(#[source] std::io::Error),
+    #[error("could not read the auth code file: {0}")]
+    ReadFile(#[source] std::io::Error),
+    #[error("could not watch the auth code file: {0}")]
+    Watch(#[source] notify::Error),
+    #[error("the http api is not running")]
+    HttpApiDisabled,
+    #[error("no notifier reply url is configured")]
+    NoNotifierReplyUrl,
+    #[error("could not get the reply from the notifier: {0}")]
+    Notifier(#[source] reqwest::Error),
+    #[error("the notifier answered with: {0}")]
+    NotifierStatus(reqwest::StatusCode),
+    #[error("could not receive the redirect: {0}")]
+    Redirect(String),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum TokenStorageError {
+    #[error("Path could not be expanded")]
+    ExpandPath(#[source] LookupError<VarError>),
+    #[error("could not read the token key: {0}")]
+    ReadKey(#[source] std::io::Error),
+    #[error("the token key is invalid: {0}")]
+    InvalidKey(String),
+    #[error("the token cache is encrypted but no key is configured")]
+    NoKey,
+    #[error("could not read the token cache: {0}")]
+    Read(#[source] std::io::Error),
+    #[error("could not write the token cache: {0}")]
+    Write(#[source] std::io::Error),
+    #[error("could not parse the token cache: {0}")]
+    Parse(#[source] serde_json::Error),
+    #[error("could not decrypt the token cache, the key might be wrong")]
+    Decrypt,
+    #[error("could not encrypt the token cache")]
+    Encrypt,
 }
 
 #[derive(Debug, thiserror::Error)]
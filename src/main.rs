@@ -1,39 +1,293 @@
+use clap::Parser;
 use lazy_static::lazy_static;
 use twba_common::prelude::*;
+use twba_local_db::prelude::{Users, Videos};
+use twba_local_db::re_exports::sea_orm::{DatabaseConnection, EntityTrait};
 
+use cli::{Cli, Command};
+use config::UploaderConf;
+use control::CONTROL;
 use prelude::*;
 
+mod api;
+mod cli;
 mod client;
+mod config;
+mod control;
 pub mod errors;
+mod notification;
+mod overrides;
 pub mod prelude;
+mod report;
+mod store;
+mod token_health;
 
 lazy_static! {
     pub(crate) static ref CONF: Conf = get_config();
+    pub(crate) static ref UPLOADER_CONF: UploaderConf = config::get_uploader_config();
 }
 #[tokio::main]
 async fn main() -> Result<()> {
     let _guard = init_tracing("twba_uploader");
     info!("Hello, world!");
 
-    run().await?;
+    let cli = Cli::parse();
+    match cli.command.unwrap_or(Command::Upload) {
+        Command::Upload => run().await?,
+        Command::Daemon => daemon().await?,
+        Command::Report { json } => report(json).await?,
+        Command::TokenHealth { json } => check_token_health(json).await?,
+        Command::EncryptTokens { dry_run } => encrypt_tokens(dry_run).await?,
+        Command::AddUser {
+            twitch_id,
+            twitch_name,
+            youtube_id,
+            timezone,
+            target_duration,
+            max_duration,
+        } => {
+            add_user(client::NewUser {
+                twitch_id,
+                twitch_name,
+                youtube_id,
+                timezone,
+                youtube_target_duration: target_duration,
+                youtube_max_duration: max_duration,
+            })
+            .await?
+        }
+        Command::RemoveUser { user_id, yes } => remove_user(user_id, yes).await?,
+        Command::SetOverrides {
+            video_id,
+            title,
+            description,
+            tags,
+            playlist_name,
+            clear,
+        } => {
+            let changes = overrides::VideoOverrides {
+                title,
+                description,
+                tags,
+                playlist_name,
+            };
+            set_overrides(video_id, changes, clear).await?
+        }
+        Command::Rollback {
+            video_id,
+            dry_run,
+            yes,
+            force,
+        } => rollback(video_id, dry_run, yes, force).await?,
+    }
 
     info!("Bye");
     Ok(())
 }
 
-#[tracing::instrument]
-async fn run() -> Result<()> {
-    trace!("run");
-
+async fn open_db() -> Result<DatabaseConnection> {
     trace!("creating db-connection with db url: {}", &CONF.db_url);
     let db = twba_local_db::open_database(Some(&CONF.db_url)).await?;
     trace!("migrating db");
     twba_local_db::migrate_db(&db).await?;
+    trace!("creating uploader tables");
+    store::init(&db).await?;
+    Ok(db)
+}
+
+#[tracing::instrument]
+async fn run() -> Result<()> {
+    trace!("run");
+    let db = open_db().await?;
+    client::validate_redirect_uris(&db).await?;
 
     trace!("creating client");
     let client = client::UploaderClient::new(db).await?;
+    client.prepare_clients().await?;
     trace!("uploading videos");
     client.upload_videos().await?;
+    if client.wait_for_pending_auth().await {
+        trace!("uploading videos of the users that authenticated");
+        client.upload_videos().await?;
+    }
+
+    Ok(())
+}
 
+#[tracing::instrument]
+async fn daemon() -> Result<()> {
+    trace!("daemon");
+    let db = open_db().await?;
+    client::validate_redirect_uris(&db).await?;
+    let conf = &UPLOADER_CONF.daemon;
+    if let Some(address) = &conf.http_address {
+        let listener = api::bind(address).await?;
+        let api_db = db.clone();
+        tokio::spawn(async move {
+            if let Err(e) = api::serve(listener, api_db).await {
+                error!("http api stopped: {}", e);
+            }
+        });
+    }
+
+    if let Some(seconds) = UPLOADER_CONF.token_health.check_interval_seconds {
+        let health_db = db.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(std::time::Duration::from_secs(seconds));
+            loop {
+                interval.tick().await;
+                if let Err(e) = token_health::check_token_health(&health_db).await {
+                    error!("error while checking the token health: {}", e);
+                }
+            }
+        });
+    }
+
+    trace!("creating client");
+    let client = client::UploaderClient::new(db).await?;
+    if let Err(e) = client.prepare_clients().await {
+        error!("could not prepare the clients: {}", e);
+    }
+    let reauth_client = client.clone();
+    tokio::spawn(async move {
+        loop {
+            for user in CONTROL.wait_for_reauth_requests().await {
+                if let Err(e) = reauth_client.start_reauth(&user).await {
+                    error!("could not start authentication for {}: {}", user, e);
+                }
+            }
+        }
+    });
+    CONTROL.set_ready(true);
+    let interval = std::time::Duration::from_secs(conf.run_interval_seconds);
+    loop {
+        trace!("uploading videos");
+        if let Err(e) = client.upload_videos().await {
+            error!("error while uploading videos: {}", e);
+        }
+        info!("waiting {:?} or a trigger for the next run", interval);
+        tokio::select! {
+            _ = tokio::time::sleep(interval) => {}
+            _ = CONTROL.wait_for_run_trigger() => {}
+        }
+    }
+}
+
+#[tracing::instrument]
+async fn report(json: bool) -> Result<()> {
+    let db = open_db().await?;
+    let report = report::create_report(&db).await?;
+    if json {
+        let json = serde_json::to_string_pretty(&report).map_err(UploaderError::SerializeReport)?;
+        println!("{}", json);
+    } else {
+        println!("{}", report);
+    }
+    Ok(())
+}
+
+#[tracing::instrument]
+async fn check_token_health(json: bool) -> Result<()> {
+    let db = open_db().await?;
+    let report = token_health::check_token_health(&db).await?;
+    if json {
+        let json = serde_json::to_string_pretty(&report).map_err(UploaderError::SerializeReport)?;
+        println!("{}", json);
+    } else {
+        println!("{}", report);
+    }
     Ok(())
 }
+
+#[tracing::instrument]
+async fn encrypt_tokens(dry_run: bool) -> Result<()> {
+    let db = open_db().await?;
+    let users = Users::find().all(&db).await?;
+    for user in users {
+        let outcome = client::encrypt_token_cache(&user.youtube_id, dry_run).await?;
+        let message = match outcome {
+            client::EncryptOutcome::Encrypted if dry_run => "would be encrypted",
+            client::EncryptOutcome::Encrypted => "encrypted",
+            client::EncryptOutcome::AlreadyEncrypted => "already encrypted",
+            client::EncryptOutcome::Missing => "no token cache",
+        };
+        println!("{} ({}): {}", user.twitch_name, user.youtube_id, message);
+    }
+    Ok(())
+}
+
+#[tracing::instrument]
+async fn add_user(new_user: client::NewUser) -> Result<()> {
+    let db = open_db().await?;
+    let client = client::UploaderClient::new(db).await?;
+    let added = client.add_user(new_user).await?;
+    println!("{}", added);
+    Ok(())
+}
+
+#[tracing::instrument]
+async fn remove_user(user_id: i32, yes: bool) -> Result<()> {
+    let db = open_db().await?;
+    let user = Users::find_by_id(user_id)
+        .one(&db)
+        .await?
+        .ok_or(UploaderError::UnknownUser(user_id))?;
+    println!(
+        "Removing user {}: {} ({})",
+        user.id, user.twitch_name, user.youtube_id
+    );
+    if !yes && !confirm("Do you really want to revoke the token and deactivate the user?")? {
+        info!("removal cancelled");
+        return Ok(());
+    }
+    let client = client::UploaderClient::new(db).await?;
+    client.remove_user(user_id).await
+}
+
+#[tracing::instrument]
+async fn set_overrides(
+    video_id: i32,
+    changes: overrides::VideoOverrides,
+    clear: bool,
+) -> Result<()> {
+    let db = open_db().await?;
+    let video = Videos::find_by_id(video_id)
+        .one(&db)
+        .await?
+        .ok_or(UploaderError::UnknownVideo(video_id))?;
+    let mut video_overrides = if clear {
+        overrides::VideoOverrides::default()
+    } else {
+        overrides::get_overrides(&db, &video).await?
+    };
+    video_overrides.merge(changes);
+    overrides::set_overrides(&db, video_id, &video_overrides).await?;
+    println!("{}", video_overrides);
+    Ok(())
+}
+
+#[tracing::instrument]
+async fn rollback(video_id: i32, dry_run: bool, yes: bool, force: bool) -> Result<()> {
+    let db = open_db().await?;
+    let client = client::UploaderClient::new(db).await?;
+    let plan = client.plan_rollback(video_id, force).await?;
+    println!("{}", plan);
+    if dry_run {
+        info!("dry run, nothing was changed");
+        return Ok(());
+    }
+    if !yes && !confirm("Do you really want to delete these from YouTube?")? {
+        info!("rollback cancelled");
+        return Ok(());
+    }
+    client.execute_rollback(&plan).await
+}
+
+fn confirm(question: &str) -> Result<bool> {
+    println!("{} [y/N]", question);
+    let mut answer = String::new();
+    std::io::stdin()
+        .read_line(&mut answer)
+        .map_err(UploaderError::ReadConfirmation)?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
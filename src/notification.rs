@@ -0,0 +1,24 @@
+use crate::prelude::*;
+use twba_common::notify::NotificationRequest;
+
+/// Sends a message to the operator through the notifier.
+///
+/// Errors are only logged, a missing notification should never stop the uploader.
+pub(crate) async fn send_notification(message: String) {
+    let notifier_url = &crate::CONF.notifier.notifier_url;
+    trace!("sending notification at: {}", notifier_url);
+    let response = reqwest::Client::new()
+        .post(notifier_url)
+        .json(&NotificationRequest { message })
+        .send()
+        .await
+        .map_err(|e| format!("Error sending request: {:?}", e));
+    match response {
+        Ok(_) => {
+            trace!("Notification sent successfully");
+        }
+        Err(e) => {
+            error!("Error sending notification: {}", e);
+        }
+    }
+}
@@ -0,0 +1,146 @@
+//! Metadata that is set by hand for a single video.
+//!
+//! Title, description and tags are kept in an uploader table, the playlist
+//! name in the `youtube_playlist_name` column of the video.
+use crate::prelude::*;
+use crate::store;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+use twba_local_db::prelude::*;
+use twba_local_db::re_exports::sea_orm::{
+    ActiveModelTrait, ActiveValue, DatabaseConnection, EntityTrait, IntoActiveModel,
+};
+
+/// The overrides of a video. Everything that is not set comes from the templates
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct VideoOverrides {
+    /// A template for the title of the parts and, without a playlist name, the playlist
+    pub title: Option<String>,
+    /// A template for the description of the parts and the playlist
+    pub description: Option<String>,
+    pub tags: Option<Vec<String>>,
+    /// A template for the title of the playlist
+    pub playlist_name: Option<String>,
+}
+
+impl VideoOverrides {
+    pub(crate) fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Takes everything that is set in `other`
+    pub(crate) fn merge(&mut self, other: VideoOverrides) {
+        self.title = other.title.or(self.title.take());
+        self.description = other.description.or(self.description.take());
+        self.tags = other.tags.or(self.tags.take());
+        self.playlist_name = other.playlist_name.or(self.playlist_name.take());
+    }
+}
+
+impl Display for VideoOverrides {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.is_empty() {
+            return write!(f, "no overrides");
+        }
+        let unset = || "-".to_string();
+        writeln!(f, "title: {}", self.title.clone().unwrap_or_else(unset))?;
+        writeln!(
+            f,
+            "playlist name: {}",
+            self.playlist_name.clone().unwrap_or_else(unset)
+        )?;
+        writeln!(
+            f,
+            "tags: {}",
+            self.tags
+                .as_ref()
+                .map_or_else(unset, |tags| tags.join(", "))
+        )?;
+        write!(
+            f,
+            "description: {}",
+            self.description.clone().unwrap_or_else(unset)
+        )
+    }
+}
+
+/// Reads the overrides of the video
+pub(crate) async fn get_overrides(
+    db: &DatabaseConnection,
+    video: &VideosModel,
+) -> Result<VideoOverrides> {
+    let stored = store::get_video_override(db, video.id).await?;
+    let (title, description, tags) = match stored {
+        Some(stored) => {
+            let tags = stored.tags.and_then(|tags| parse_tags(video.id, &tags));
+            (stored.title, stored.description, tags)
+        }
+        None => (None, None, None),
+    };
+    Ok(VideoOverrides {
+        title,
+        description,
+        tags,
+        playlist_name: Some(video.youtube_playlist_name.clone()).filter(|name| !name.is_empty()),
+    })
+}
+
+fn parse_tags(video_id: i32, tags: &str) -> Option<Vec<String>> {
+    match serde_json::from_str(tags) {
+        Ok(tags) => Some(tags),
+        Err(e) => {
+            warn!("ignoring invalid tags of video {}: {}", video_id, e);
+            None
+        }
+    }
+}
+
+/// Replaces the overrides of the video
+#[tracing::instrument(skip(db))]
+pub(crate) async fn set_overrides(
+    db: &DatabaseConnection,
+    video_id: i32,
+    overrides: &VideoOverrides,
+) -> Result<()> {
+    let video = Videos::find_by_id(video_id)
+        .one(db)
+        .await?
+        .ok_or(UploaderError::UnknownVideo(video_id))?;
+
+    let VideoOverrides {
+        title,
+        description,
+        tags,
+        playlist_name,
+    } = overrides.clone();
+    if title.is_none() && description.is_none() && tags.is_none() {
+        store::delete_video_override(db, video_id).await?;
+    } else {
+        let tags = tags
+            .map(|tags| serde_json::to_string(&tags))
+            .transpose()
+            .map_err(UploaderError::SerializeOverrides)?;
+        store::set_video_override(
+            db,
+            store::video_override::Model {
+                video_id,
+                title,
+                description,
+                tags,
+                updated_at: Utc::now().to_rfc3339(),
+            },
+        )
+        .await?;
+    }
+
+    let playlist_name = playlist_name.unwrap_or_default();
+    if playlist_name != video.youtube_playlist_name {
+        let mut video = video.into_active_model();
+        video.youtube_playlist_name = ActiveValue::Set(playlist_name);
+        video.update(db).await?;
+    }
+    info!("set overrides of video {}", video_id);
+    Ok(())
+}
@@ -0,0 +1,415 @@
+use crate::client::data::part_range_seconds;
+use crate::client::quota_cost;
+use crate::prelude::*;
+use crate::{store, CONF, UPLOADER_CONF};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+use twba_local_db::prelude::*;
+use twba_local_db::re_exports::sea_orm::{
+    ColumnTrait, DatabaseConnection, EntityTrait, Order, QueryFilter, QueryOrder,
+};
+
+/// How many of the most recent failures are shown per user
+const RECENT_FAILURES: usize = 5;
+
+#[derive(Debug, Serialize)]
+pub(crate) struct Report {
+    pub quota_day: String,
+    pub daily_quota: i64,
+    pub quota_used_today: i64,
+    /// The days it takes to upload everything that is pending, if all of the
+    /// daily quota is used for it
+    pub estimated_days_to_clear: f64,
+    pub users: Vec<UserReport>,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct UserReport {
+    pub user_id: i32,
+    pub twitch_name: String,
+    pub youtube_name: String,
+    pub videos_per_status: BTreeMap<String, usize>,
+    pub pending_videos: usize,
+    pub pending_bytes: u64,
+    pub pending_seconds: i64,
+    pub pending_quota: i64,
+    pub last_upload: Option<LastUpload>,
+    pub recent_failures: Vec<Failure>,
+    pub quota_used_today: i64,
+    pub estimated_days_to_clear: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct LastUpload {
+    pub video_id: i32,
+    pub uploaded_at: String,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct Failure {
+    pub video_id: i32,
+    pub name: String,
+    pub fail_count: i32,
+    pub fail_reason: Option<String>,
+    /// Not known for failures from before it was recorded
+    pub failed_at: Option<String>,
+}
+
+fn is_pending(status: &Status) -> bool {
+    matches!(
+        status,
+        Status::Split | Status::Uploading | Status::PartiallyUploaded
+    )
+}
+
+#[tracing::instrument(skip(db))]
+pub(crate) async fn create_report(db: &DatabaseConnection) -> Result<Report> {
+    let users = Users::find().all(db).await?;
+    let videos = Videos::find()
+        .order_by(VideosColumn::CreatedAt, Order::Desc)
+        .all(db)
+        .await?;
+    let uploads = VideoUpload::find()
+        .filter(VideoUploadColumn::YoutubeVideoId.is_not_null())
+        .all(db)
+        .await?;
+    let quota_usage: HashMap<i32, i64> = store::get_quota_usage_today(db)
+        .await?
+        .into_iter()
+        .collect();
+    let last_uploads: HashMap<i32, store::last_upload::Model> = store::get_last_uploads(db)
+        .await?
+        .into_iter()
+        .map(|upload| (upload.user_id, upload))
+        .collect();
+    let failed_at: HashMap<i32, String> = store::get_video_failures(db)
+        .await?
+        .into_iter()
+        .map(|failure| (failure.video_id, failure.failed_at))
+        .collect();
+    let daily_quota = UPLOADER_CONF.quota.daily_limit;
+
+    let mut user_reports = Vec::new();
+    for user in users {
+        let user_videos: Vec<&VideosModel> =
+            videos.iter().filter(|v| v.user_id == user.id).collect();
+
+        let mut videos_per_status = BTreeMap::new();
+        for video in &user_videos {
+            *videos_per_status
+                .entry(format!("{:?}", video.status))
+                .or_insert(0) += 1;
+        }
+
+        let pending: Vec<&VideosModel> = user_videos
+            .iter()
+            .copied()
+            .filter(|v| is_pending(&v.status))
+            .collect();
+        let pending_bytes = pending.iter().map(|v| get_parts_size(v.id)).sum();
+        let mut pending_seconds = 0;
+        let mut pending_quota = 0;
+        for video in &pending {
+            let uploaded: Vec<usize> = uploads
+                .iter()
+                .filter(|u| u.video_id == video.id)
+                .map(|u| u.part as usize)
+                .collect();
+            let durations = store::get_part_durations(db, video.id).await?;
+            let durations = complete_part_durations(video.part_count, &durations);
+            pending_seconds += remaining_seconds(video, &user, &uploaded, &durations);
+            pending_quota += estimate_quota(video, uploaded.len() as i64);
+        }
+
+        let recent_failures = recent_failures(&user_videos, &failed_at);
+
+        user_reports.push(UserReport {
+            user_id: user.id,
+            twitch_name: user.twitch_name,
+            youtube_name: user.youtube_name,
+            videos_per_status,
+            pending_videos: pending.len(),
+            pending_bytes,
+            pending_seconds,
+            pending_quota,
+            last_upload: last_uploads.get(&user.id).map(|upload| LastUpload {
+                video_id: upload.video_id,
+                uploaded_at: upload.uploaded_at.clone(),
+            }),
+            recent_failures,
+            quota_used_today: quota_usage.get(&user.id).copied().unwrap_or(0),
+            estimated_days_to_clear: days_to_clear(pending_quota, daily_quota),
+        });
+    }
+
+    let pending_quota = user_reports.iter().map(|u| u.pending_quota).sum();
+    Ok(Report {
+        quota_day: store::quota_day().to_string(),
+        daily_quota,
+        quota_used_today: quota_usage.values().sum(),
+        estimated_days_to_clear: days_to_clear(pending_quota, daily_quota),
+        users: user_reports,
+    })
+}
+
+/// The most recent failures, by the time they failed. Failures without a
+/// time come last, newest video first.
+fn recent_failures(videos: &[&VideosModel], failed_at: &HashMap<i32, String>) -> Vec<Failure> {
+    let mut failed: Vec<&VideosModel> = videos
+        .iter()
+        .copied()
+        .filter(|v| v.fail_count > 0 && !matches!(v.status, Status::Uploaded))
+        .collect();
+    // stable, so videos without a time keep their order
+    failed.sort_by(|a, b| failed_at.get(&b.id).cmp(&failed_at.get(&a.id)));
+    failed
+        .into_iter()
+        .take(RECENT_FAILURES)
+        .map(|v| Failure {
+            video_id: v.id,
+            name: v.name.clone(),
+            fail_count: v.fail_count,
+            fail_reason: v.fail_reason.clone(),
+            failed_at: failed_at.get(&v.id).cloned(),
+        })
+        .collect()
+}
+
+/// The lengths of all parts in seconds, or nothing if one is not known
+fn complete_part_durations(part_count: i32, durations: &[store::part_duration::Model]) -> Vec<u64> {
+    (1..=part_count)
+        .map(|part| {
+            durations
+                .iter()
+                .find(|d| d.part == part)
+                .map(|d| (d.duration_ms.max(0) as f64 / 1000.0).round() as u64)
+        })
+        .collect::<Option<Vec<u64>>>()
+        .unwrap_or_default()
+}
+
+/// The seconds of the video that are not uploaded yet
+fn remaining_seconds(
+    video: &VideosModel,
+    user: &UsersModel,
+    uploaded_parts: &[usize],
+    part_durations: &[u64],
+) -> i64 {
+    let uploaded: u64 = uploaded_parts
+        .iter()
+        .map(|part| {
+            let (start, end) = part_range_seconds(video, user, *part, part_durations);
+            end - start
+        })
+        .sum();
+    (video.duration.max(0) as i64 - uploaded as i64).max(0)
+}
+
+/// The quota that is still needed to upload a video with the given amount of
+/// already uploaded parts
+fn estimate_quota(video: &VideosModel, uploaded_parts: i64) -> i64 {
+    let remaining_parts = (video.part_count as i64 - uploaded_parts).max(0);
+    let playlist = if video.youtube_playlist_id.is_none() {
+        quota_cost::PLAYLIST_INSERT
+    } else {
+        0
+    };
+    playlist + remaining_parts * (quota_cost::VIDEO_INSERT + quota_cost::PLAYLIST_ITEM_INSERT)
+}
+
+fn days_to_clear(pending_quota: i64, daily_quota: i64) -> f64 {
+    if daily_quota <= 0 {
+        return f64::INFINITY;
+    }
+    pending_quota as f64 / daily_quota as f64
+}
+
+/// The size of all part files of a video that are still on disk
+fn get_parts_size(video_id: i32) -> u64 {
+    let folder = Path::new(&CONF.download_folder_path).join(video_id.to_string());
+    let Ok(entries) = folder.read_dir() else {
+        return 0;
+    };
+    entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.metadata().ok())
+        .map(|metadata| metadata.len())
+        .sum()
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "quota day {}: {}/{} units used, ~{:.1} days to clear the backlog",
+            self.quota_day, self.quota_used_today, self.daily_quota, self.estimated_days_to_clear
+        )?;
+        writeln!(f)?;
+        writeln!(
+            f,
+            "{:>4} {:<20} {:>7} {:>10} {:>9} {:>7} {:>6} {:<25}",
+            "id", "user", "pending", "size", "duration", "quota", "days", "last upload"
+        )?;
+        for user in &self.users {
+            writeln!(
+                f,
+                "{:>4} {:<20} {:>7} {:>10} {:>9} {:>7} {:>6.1} {:<25}",
+                user.user_id,
+                user.twitch_name,
+                user.pending_videos,
+                format_bytes(user.pending_bytes),
+                format_duration(user.pending_seconds),
+                user.quota_used_today,
+                user.estimated_days_to_clear,
+                user.last_upload
+                    .as_ref()
+                    .map(|u| u.uploaded_at.as_str())
+                    .unwrap_or("-"),
+            )?;
+        }
+        for user in &self.users {
+            writeln!(f)?;
+            let statuses: Vec<String> = user
+                .videos_per_status
+                .iter()
+                .map(|(status, count)| format!("{}: {}", status, count))
+                .collect();
+            writeln!(f, "{} ({})", user.twitch_name, statuses.join(", "))?;
+            for failure in &user.recent_failures {
+                let reason = failure
+                    .fail_reason
+                    .as_deref()
+                    .and_then(|r| r.lines().next())
+                    .unwrap_or("");
+                writeln!(
+                    f,
+                    "  failed {}x: {} '{}': {}",
+                    failure.fail_count, failure.video_id, failure.name, reason
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1}{}", value, UNITS[unit])
+}
+
+fn format_duration(seconds: i64) -> String {
+    format!("{}h{:0>2}m", seconds / 3600, (seconds % 3600) / 60)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn video(id: i32) -> VideosModel {
+        VideosModel {
+            id,
+            status: Status::Split,
+            user_id: 0,
+            name: format!("video {}", id),
+            created_at: "2023-10-09T05:33:59+00:00".to_string(),
+            part_count: 3,
+            duration: 3 * 3600,
+            twitch_id: String::new(),
+            twitch_preview_image_url: None,
+            twitch_download_url: None,
+            youtube_id: None,
+            youtube_playlist_name: String::new(),
+            youtube_preview_image_url: None,
+            youtube_playlist_id: None,
+            youtube_playlist_created_at: None,
+            fail_count: 0,
+            fail_reason: None,
+        }
+    }
+
+    fn user() -> UsersModel {
+        UsersModel {
+            id: 0,
+            twitch_id: String::new(),
+            twitch_name: String::new(),
+            twitch_profile_image_url: None,
+            youtube_id: String::new(),
+            youtube_name: String::new(),
+            youtube_profile_image_url: None,
+            youtube_target_duration: 3600,
+            youtube_max_duration: 7200,
+            active: true,
+            timezone: "+00:00".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_days_to_clear() {
+        assert_eq!(0.5, days_to_clear(5000, 10000));
+        assert_eq!(f64::INFINITY, days_to_clear(5000, 0));
+    }
+
+    #[test]
+    fn test_estimate_quota() {
+        let mut v = video(1);
+        let per_part = quota_cost::VIDEO_INSERT + quota_cost::PLAYLIST_ITEM_INSERT;
+        assert_eq!(
+            quota_cost::PLAYLIST_INSERT + 3 * per_part,
+            estimate_quota(&v, 0)
+        );
+        v.youtube_playlist_id = Some("PL".to_string());
+        assert_eq!(per_part, estimate_quota(&v, 2));
+        assert_eq!(0, estimate_quota(&v, 5));
+    }
+
+    #[test]
+    fn test_remaining_seconds() {
+        let v = video(1);
+        assert_eq!(3 * 3600, remaining_seconds(&v, &user(), &[], &[]));
+        assert_eq!(3600, remaining_seconds(&v, &user(), &[1, 2], &[]));
+        assert_eq!(
+            3 * 3600 - 1000,
+            remaining_seconds(&v, &user(), &[2], &[5000, 1000, 4800])
+        );
+    }
+
+    #[test]
+    fn test_complete_part_durations() {
+        let duration = |part, duration_ms| store::part_duration::Model {
+            video_id: 1,
+            part,
+            duration_ms,
+        };
+        assert_eq!(
+            vec![2, 3],
+            complete_part_durations(2, &[duration(2, 3000), duration(1, 1600)])
+        );
+        assert!(complete_part_durations(2, &[duration(1, 1600)]).is_empty());
+    }
+
+    #[test]
+    fn test_recent_failures_by_time() {
+        let mut videos: Vec<VideosModel> = (1..=3).map(video).collect();
+        for v in &mut videos {
+            v.fail_count = 1;
+        }
+        let refs: Vec<&VideosModel> = videos.iter().collect();
+        let failed_at = HashMap::from([
+            (2, "2024-01-01T00:00:00+00:00".to_string()),
+            (3, "2024-02-01T00:00:00+00:00".to_string()),
+        ]);
+        let ids: Vec<i32> = recent_failures(&refs, &failed_at)
+            .iter()
+            .map(|f| f.video_id)
+            .collect();
+        assert_eq!(vec![3, 2, 1], ids);
+    }
+}
@@ -0,0 +1,327 @@
+//! Tables that only the uploader uses.
+//!
+//! They live in the same database as the rest of twba, but are created and
+//! owned by the uploader, so they are set up here instead of in the
+//! migrations of `twba_local_db`.
+use crate::prelude::*;
+use chrono::{Duration, NaiveDate, Utc};
+use twba_local_db::re_exports::sea_orm::sea_query::OnConflict;
+use twba_local_db::re_exports::sea_orm::{
+    ActiveModelTrait, ActiveValue, ColumnTrait, ConnectionTrait, DatabaseConnection, EntityTrait,
+    IntoActiveModel, QueryFilter, Schema,
+};
+
+pub(crate) mod auth_state;
+pub(crate) mod last_upload;
+pub(crate) mod oauth_token;
+pub(crate) mod part_duration;
+pub(crate) mod quota_usage;
+pub(crate) mod token_health;
+pub(crate) mod video_failure;
+pub(crate) mod video_override;
+
+/// Creates all uploader tables that do not exist yet
+pub(crate) async fn init(db: &DatabaseConnection) -> Result<()> {
+    let backend = db.get_database_backend();
+    let schema = Schema::new(backend);
+    let tables = [
+        schema.create_table_from_entity(quota_usage::Entity),
+        schema.create_table_from_entity(last_upload::Entity),
+        schema.create_table_from_entity(auth_state::Entity),
+        schema.create_table_from_entity(token_health::Entity),
+        schema.create_table_from_entity(oauth_token::Entity),
+        schema.create_table_from_entity(video_override::Entity),
+        schema.create_table_from_entity(part_duration::Entity),
+        schema.create_table_from_entity(video_failure::Entity),
+    ];
+    for mut table in tables {
+        table.if_not_exists();
+        db.execute(backend.build(&table)).await?;
+    }
+    Ok(())
+}
+
+/// The day the YouTube quota is currently counted for.
+///
+/// The quota resets at midnight pacific time, this uses a fixed offset of
+/// UTC-8 as an approximation.
+pub(crate) fn quota_day() -> NaiveDate {
+    (Utc::now() - Duration::hours(8)).date_naive()
+}
+
+pub(crate) async fn add_quota_usage(
+    db: &DatabaseConnection,
+    user_id: i32,
+    units: i64,
+) -> Result<()> {
+    let day = quota_day().to_string();
+    let existing = quota_usage::Entity::find()
+        .filter(quota_usage::Column::Day.eq(day.clone()))
+        .filter(quota_usage::Column::UserId.eq(user_id))
+        .one(db)
+        .await?;
+    match existing {
+        Some(existing) => {
+            let used = existing.units + units;
+            let mut existing = existing.into_active_model();
+            existing.units = ActiveValue::Set(used);
+            existing.update(db).await?;
+        }
+        None => {
+            quota_usage::ActiveModel {
+                day: ActiveValue::Set(day),
+                user_id: ActiveValue::Set(user_id),
+                units: ActiveValue::Set(units),
+            }
+            .insert(db)
+            .await?;
+        }
+    }
+    Ok(())
+}
+
+/// The quota units used today, per user
+pub(crate) async fn get_quota_usage_today(db: &DatabaseConnection) -> Result<Vec<(i32, i64)>> {
+    let usage = quota_usage::Entity::find()
+        .filter(quota_usage::Column::Day.eq(quota_day().to_string()))
+        .all(db)
+        .await?;
+    Ok(usage.into_iter().map(|u| (u.user_id, u.units)).collect())
+}
+
+pub(crate) async fn set_last_upload(
+    db: &DatabaseConnection,
+    user_id: i32,
+    video_id: i32,
+) -> Result<()> {
+    let model = last_upload::ActiveModel {
+        user_id: ActiveValue::Set(user_id),
+        video_id: ActiveValue::Set(video_id),
+        uploaded_at: ActiveValue::Set(Utc::now().to_rfc3339()),
+    };
+    let exists = last_upload::Entity::find_by_id(user_id)
+        .one(db)
+        .await?
+        .is_some();
+    if exists {
+        model.update(db).await?;
+    } else {
+        model.insert(db).await?;
+    }
+    Ok(())
+}
+
+pub(crate) async fn get_last_uploads(db: &DatabaseConnection) -> Result<Vec<last_upload::Model>> {
+    Ok(last_upload::Entity::find().all(db).await?)
+}
+
+pub(crate) async fn get_auth_state(
+    db: &DatabaseConnection,
+    user_id: i32,
+) -> Result<Option<auth_state::Model>> {
+    Ok(auth_state::Entity::find_by_id(user_id).one(db).await?)
+}
+
+/// Marks the user as needing to authenticate again.
+///
+/// Does nothing if the user is already marked, so the reminders keep their schedule.
+pub(crate) async fn mark_needs_reauth(
+    db: &DatabaseConnection,
+    user_id: i32,
+    reason: impl Into<String>,
+) -> Result<()> {
+    let existing = get_auth_state(db, user_id).await?;
+    if existing.as_ref().is_some_and(|state| state.needs_reauth) {
+        return Ok(());
+    }
+    let model = auth_state::ActiveModel {
+        user_id: ActiveValue::Set(user_id),
+        needs_reauth: ActiveValue::Set(true),
+        reason: ActiveValue::Set(Some(reason.into())),
+        requested_at: ActiveValue::Set(Some(Utc::now().to_rfc3339())),
+        last_reminder_at: ActiveValue::Set(None),
+        reminder_count: ActiveValue::Set(0),
+    };
+    match existing {
+        Some(_) => model.update(db).await?,
+        None => model.insert(db).await?,
+    };
+    Ok(())
+}
+
+pub(crate) async fn clear_needs_reauth(db: &DatabaseConnection, user_id: i32) -> Result<()> {
+    if let Some(state) = get_auth_state(db, user_id).await? {
+        let mut state = state.into_active_model();
+        state.needs_reauth = ActiveValue::Set(false);
+        state.reason = ActiveValue::Set(None);
+        state.last_reminder_at = ActiveValue::Set(None);
+        state.reminder_count = ActiveValue::Set(0);
+        state.update(db).await?;
+    }
+    Ok(())
+}
+
+pub(crate) async fn record_reauth_reminder(
+    db: &DatabaseConnection,
+    state: auth_state::Model,
+) -> Result<()> {
+    let reminder_count = state.reminder_count + 1;
+    let mut state = state.into_active_model();
+    state.last_reminder_at = ActiveValue::Set(Some(Utc::now().to_rfc3339()));
+    state.reminder_count = ActiveValue::Set(reminder_count);
+    state.update(db).await?;
+    Ok(())
+}
+
+pub(crate) async fn get_token_health(
+    db: &DatabaseConnection,
+    user_id: i32,
+) -> Result<Option<token_health::Model>> {
+    Ok(token_health::Entity::find_by_id(user_id).one(db).await?)
+}
+
+pub(crate) async fn set_token_health(
+    db: &DatabaseConnection,
+    health: token_health::Model,
+) -> Result<()> {
+    let exists = get_token_health(db, health.user_id).await?.is_some();
+    let model = health.into_active_model().reset_all();
+    if exists {
+        model.update(db).await?;
+    } else {
+        model.insert(db).await?;
+    }
+    Ok(())
+}
+
+pub(crate) async fn get_oauth_tokens(
+    db: &DatabaseConnection,
+    channel_id: &str,
+) -> Result<Vec<oauth_token::Model>> {
+    Ok(oauth_token::Entity::find()
+        .filter(oauth_token::Column::ChannelId.eq(channel_id))
+        .all(db)
+        .await?)
+}
+
+pub(crate) async fn set_oauth_token(
+    db: &DatabaseConnection,
+    channel_id: &str,
+    scopes: &str,
+    token: String,
+) -> Result<()> {
+    let model = oauth_token::ActiveModel {
+        channel_id: ActiveValue::Set(channel_id.to_string()),
+        scopes: ActiveValue::Set(scopes.to_string()),
+        token: ActiveValue::Set(token),
+        updated_at: ActiveValue::Set(Utc::now().to_rfc3339()),
+    };
+    // a single statement, so hosts that share the table can not race
+    oauth_token::Entity::insert(model)
+        .on_conflict(
+            OnConflict::columns([oauth_token::Column::ChannelId, oauth_token::Column::Scopes])
+                .update_columns([oauth_token::Column::Token, oauth_token::Column::UpdatedAt])
+                .to_owned(),
+        )
+        .exec(db)
+        .await?;
+    Ok(())
+}
+
+pub(crate) async fn delete_oauth_tokens(db: &DatabaseConnection, channel_id: &str) -> Result<()> {
+    oauth_token::Entity::delete_many()
+        .filter(oauth_token::Column::ChannelId.eq(channel_id))
+        .exec(db)
+        .await?;
+    Ok(())
+}
+
+pub(crate) async fn get_video_override(
+    db: &DatabaseConnection,
+    video_id: i32,
+) -> Result<Option<video_override::Model>> {
+    Ok(video_override::Entity::find_by_id(video_id).one(db).await?)
+}
+
+pub(crate) async fn set_video_override(
+    db: &DatabaseConnection,
+    video_override: video_override::Model,
+) -> Result<()> {
+    let exists = get_video_override(db, video_override.video_id)
+        .await?
+        .is_some();
+    let model = video_override.into_active_model().reset_all();
+    if exists {
+        model.update(db).await?;
+    } else {
+        model.insert(db).await?;
+    }
+    Ok(())
+}
+
+pub(crate) async fn delete_video_override(db: &DatabaseConnection, video_id: i32) -> Result<()> {
+    video_override::Entity::delete_by_id(video_id)
+        .exec(db)
+        .await?;
+    Ok(())
+}
+
+pub(crate) async fn get_part_durations(
+    db: &DatabaseConnection,
+    video_id: i32,
+) -> Result<Vec<part_duration::Model>> {
+    Ok(part_duration::Entity::find()
+        .filter(part_duration::Column::VideoId.eq(video_id))
+        .all(db)
+        .await?)
+}
+
+pub(crate) async fn set_part_duration(
+    db: &DatabaseConnection,
+    video_id: i32,
+    part: i32,
+    duration_ms: i64,
+) -> Result<()> {
+    let model = part_duration::Model {
+        video_id,
+        part,
+        duration_ms,
+    }
+    .into_active_model()
+    .reset_all();
+    let exists = part_duration::Entity::find_by_id((video_id, part))
+        .one(db)
+        .await?
+        .is_some();
+    if exists {
+        model.update(db).await?;
+    } else {
+        model.insert(db).await?;
+    }
+    Ok(())
+}
+
+pub(crate) async fn set_video_failure(db: &DatabaseConnection, video_id: i32) -> Result<()> {
+    let model = video_failure::Model {
+        video_id,
+        failed_at: Utc::now().to_rfc3339(),
+    }
+    .into_active_model()
+    .reset_all();
+    let exists = video_failure::Entity::find_by_id(video_id)
+        .one(db)
+        .await?
+        .is_some();
+    if exists {
+        model.update(db).await?;
+    } else {
+        model.insert(db).await?;
+    }
+    Ok(())
+}
+
+pub(crate) async fn get_video_failures(
+    db: &DatabaseConnection,
+) -> Result<Vec<video_failure::Model>> {
+    Ok(video_failure::Entity::find().all(db).await?)
+}
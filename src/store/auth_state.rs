@@ -0,0 +1,20 @@
+use twba_local_db::re_exports::sea_orm;
+use twba_local_db::re_exports::sea_orm::entity::prelude::*;
+
+/// Users that have to authenticate again before their videos can be uploaded
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel)]
+#[sea_orm(table_name = "uploader_auth_state")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub user_id: i32,
+    pub needs_reauth: bool,
+    pub reason: Option<String>,
+    pub requested_at: Option<String>,
+    pub last_reminder_at: Option<String>,
+    pub reminder_count: i32,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
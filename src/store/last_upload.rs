@@ -0,0 +1,17 @@
+use twba_local_db::re_exports::sea_orm;
+use twba_local_db::re_exports::sea_orm::entity::prelude::*;
+
+/// The last video of a user that was completely uploaded
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel)]
+#[sea_orm(table_name = "uploader_last_upload")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub user_id: i32,
+    pub video_id: i32,
+    pub uploaded_at: String,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
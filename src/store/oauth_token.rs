@@ -0,0 +1,21 @@
+use twba_local_db::re_exports::sea_orm;
+use twba_local_db::re_exports::sea_orm::entity::prelude::*;
+
+/// The oauth tokens of a channel, one row per set of scopes
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel)]
+#[sea_orm(table_name = "uploader_oauth_token")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub channel_id: String,
+    /// The sorted scopes, separated by spaces
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub scopes: String,
+    /// The token as json, encrypted and base64 encoded if a key is configured
+    pub token: String,
+    pub updated_at: String,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
@@ -0,0 +1,18 @@
+use twba_local_db::re_exports::sea_orm;
+use twba_local_db::re_exports::sea_orm::entity::prelude::*;
+
+/// The probed length of a part, kept because the file is deleted after the upload
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel)]
+#[sea_orm(table_name = "uploader_part_duration")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub video_id: i32,
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub part: i32,
+    pub duration_ms: i64,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
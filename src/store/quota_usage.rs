@@ -0,0 +1,18 @@
+use twba_local_db::re_exports::sea_orm;
+use twba_local_db::re_exports::sea_orm::entity::prelude::*;
+
+/// The YouTube api quota units used by a user on a day
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel)]
+#[sea_orm(table_name = "uploader_quota_usage")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub day: String,
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub user_id: i32,
+    pub units: i64,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
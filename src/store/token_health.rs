@@ -0,0 +1,22 @@
+use twba_local_db::re_exports::sea_orm;
+use twba_local_db::re_exports::sea_orm::entity::prelude::*;
+
+/// The result of the last token health check of a user
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel)]
+#[sea_orm(table_name = "uploader_token_health")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub user_id: i32,
+    pub status: String,
+    pub detail: Option<String>,
+    pub checked_at: String,
+    /// Identifies the refresh token, to notice when it was replaced
+    pub refresh_token_fingerprint: Option<String>,
+    /// When the current refresh token was first seen
+    pub refresh_token_seen_at: Option<String>,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
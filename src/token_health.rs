@@ -0,0 +1,295 @@
+use crate::client::{check_cached_token, TokenCheck};
+use crate::config::TokenHealthConf;
+use crate::notification::send_notification;
+use crate::prelude::*;
+use crate::{store, UPLOADER_CONF};
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+use std::fmt::{Display, Formatter};
+use twba_local_db::prelude::*;
+use twba_local_db::re_exports::sea_orm::DatabaseConnection;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum TokenStatus {
+    Valid,
+    /// Still works, but google will stop accepting it soon
+    Expiring,
+    /// Google does not accept the refresh token anymore
+    Revoked,
+    /// There is no cached token for the user
+    Missing,
+}
+
+impl Display for TokenStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let status = match self {
+            TokenStatus::Valid => "valid",
+            TokenStatus::Expiring => "expiring",
+            TokenStatus::Revoked => "revoked",
+            TokenStatus::Missing => "missing",
+        };
+        write!(f, "{}", status)
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct TokenHealthReport {
+    pub checked_at: String,
+    pub users: Vec<UserTokenHealth>,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct UserTokenHealth {
+    pub user_id: i32,
+    pub twitch_name: String,
+    pub youtube_id: String,
+    /// Not set if the check itself failed
+    pub status: Option<TokenStatus>,
+    pub detail: Option<String>,
+    pub expires_at: Option<String>,
+    pub reauth_link: Option<String>,
+}
+
+/// Checks the cached tokens of all watched users.
+///
+/// The result is stored per user and the operator is notified when a token
+/// becomes expiring, revoked or missing.
+#[tracing::instrument(skip(db))]
+pub(crate) async fn check_token_health(db: &DatabaseConnection) -> Result<TokenHealthReport> {
+    let users = twba_local_db::get_watched_users(db).await?;
+    let mut results = Vec::new();
+    for user in users {
+        let health = match check_user(db, &user).await {
+            Ok(health) => health,
+            Err(e) => {
+                error!("could not check the token of user {}: {}", user.id, e);
+                UserTokenHealth {
+                    user_id: user.id,
+                    twitch_name: user.twitch_name,
+                    youtube_id: user.youtube_id,
+                    status: None,
+                    detail: Some(e.to_string()),
+                    expires_at: None,
+                    reauth_link: None,
+                }
+            }
+        };
+        results.push(health);
+    }
+    Ok(TokenHealthReport {
+        checked_at: Utc::now().to_rfc3339(),
+        users: results,
+    })
+}
+
+async fn check_user(db: &DatabaseConnection, user: &UsersModel) -> Result<UserTokenHealth> {
+    let check = check_cached_token(db, &user.youtube_id).await?;
+    let previous = store::get_token_health(db, user.id).await?;
+    let now = Utc::now();
+
+    let (fingerprint, seen_at) = match &check {
+        TokenCheck::Refreshed { fingerprint, .. } => {
+            let seen_at = previous
+                .as_ref()
+                .filter(|p| p.refresh_token_fingerprint.as_ref() == Some(fingerprint))
+                .and_then(|p| p.refresh_token_seen_at.as_ref())
+                .and_then(|date| DateTime::parse_from_rfc3339(date).ok())
+                .map(|date| date.to_utc())
+                .unwrap_or(now);
+            (Some(fingerprint.clone()), Some(seen_at))
+        }
+        _ => (None, None),
+    };
+    let (status, expires_at) = classify(&check, seen_at, now, &UPLOADER_CONF.token_health);
+    let detail = match &check {
+        TokenCheck::Refreshed { .. } => None,
+        TokenCheck::Revoked(detail) | TokenCheck::Missing(detail) => Some(detail.clone()),
+    };
+    info!("token of user {} is {}", user.id, status);
+
+    // never with the api token, notifications end up in chat histories
+    let reauth_link = UPLOADER_CONF
+        .daemon
+        .api_url()
+        .map(|url| format!("{}/auth/{}/start", url, user.youtube_id));
+    let changed = previous
+        .as_ref()
+        .map_or(true, |p| p.status != status.to_string());
+    if status != TokenStatus::Valid && changed {
+        send_alert(user, status, detail.as_deref(), reauth_link.as_deref()).await;
+    }
+    if matches!(status, TokenStatus::Revoked | TokenStatus::Missing) {
+        let reason = format!("token is {}", status);
+        store::mark_needs_reauth(db, user.id, reason).await?;
+    }
+
+    store::set_token_health(
+        db,
+        store::token_health::Model {
+            user_id: user.id,
+            status: status.to_string(),
+            detail: detail.clone(),
+            checked_at: now.to_rfc3339(),
+            refresh_token_fingerprint: fingerprint,
+            refresh_token_seen_at: seen_at.map(|date| date.to_rfc3339()),
+        },
+    )
+    .await?;
+
+    Ok(UserTokenHealth {
+        user_id: user.id,
+        twitch_name: user.twitch_name.clone(),
+        youtube_id: user.youtube_id.clone(),
+        status: Some(status),
+        detail,
+        expires_at: expires_at.map(|date| date.to_rfc3339()),
+        reauth_link,
+    })
+}
+
+/// Classifies the result of a refresh.
+///
+/// The expiry of the refresh token comes from google if it sent one, or
+/// from the configured lifetime, counted from when the token was first seen.
+fn classify(
+    check: &TokenCheck,
+    seen_at: Option<DateTime<Utc>>,
+    now: DateTime<Utc>,
+    conf: &TokenHealthConf,
+) -> (TokenStatus, Option<DateTime<Utc>>) {
+    match check {
+        TokenCheck::Revoked(_) => (TokenStatus::Revoked, None),
+        TokenCheck::Missing(_) => (TokenStatus::Missing, None),
+        TokenCheck::Refreshed {
+            refresh_token_expires_in,
+            ..
+        } => {
+            let expires_at = match refresh_token_expires_in {
+                Some(seconds) => Some(now + Duration::seconds(*seconds)),
+                None => conf
+                    .refresh_token_lifetime_days
+                    .zip(seen_at)
+                    .map(|(days, seen_at)| seen_at + Duration::days(days)),
+            };
+            let warning = Duration::days(conf.expiring_warning_days);
+            match expires_at {
+                Some(expires_at) if expires_at - now < warning => {
+                    (TokenStatus::Expiring, Some(expires_at))
+                }
+                _ => (TokenStatus::Valid, expires_at),
+            }
+        }
+    }
+}
+
+async fn send_alert(
+    user: &UsersModel,
+    status: TokenStatus,
+    detail: Option<&str>,
+    reauth_link: Option<&str>,
+) {
+    let mut message = format!(
+        "The token of {} ({}) is {}",
+        user.twitch_name, user.youtube_id, status
+    );
+    if let Some(detail) = detail {
+        message.push_str(&format!(": {}", detail));
+    }
+    match reauth_link {
+        Some(link) if UPLOADER_CONF.daemon.api_token.is_some() => message.push_str(&format!(
+            "\nRe-authenticate here (add ?token= with the api token): {}",
+            link
+        )),
+        Some(link) => message.push_str(&format!("\nRe-authenticate here: {}", link)),
+        None => message.push_str("\nRun the uploader to authenticate again."),
+    }
+    send_notification(message).await;
+}
+
+impl Display for TokenHealthReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "checked at {}", self.checked_at)?;
+        writeln!(f)?;
+        writeln!(
+            f,
+            "{:>4} {:<20} {:<26} {:<9} {:<25}",
+            "id", "user", "channel", "status", "expires"
+        )?;
+        for user in &self.users {
+            writeln!(
+                f,
+                "{:>4} {:<20} {:<26} {:<9} {:<25}",
+                user.user_id,
+                user.twitch_name,
+                user.youtube_id,
+                user.status.map_or("error".to_string(), |s| s.to_string()),
+                user.expires_at.as_deref().unwrap_or("-"),
+            )?;
+            if let Some(detail) = &user.detail {
+                writeln!(f, "     {}", detail)?;
+            }
+            if user.status != Some(TokenStatus::Valid) {
+                if let Some(link) = &user.reauth_link {
+                    writeln!(f, "     re-authenticate: {}", link)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn refreshed(expires_in: Option<i64>) -> TokenCheck {
+        TokenCheck::Refreshed {
+            fingerprint: "f".to_string(),
+            refresh_token_expires_in: expires_in,
+        }
+    }
+
+    #[test]
+    fn test_classify_without_expiry() {
+        let conf = TokenHealthConf::default();
+        let now = Utc::now();
+        let (status, expires_at) = classify(&refreshed(None), Some(now), now, &conf);
+        assert_eq!(status, TokenStatus::Valid);
+        assert_eq!(expires_at, None);
+    }
+
+    #[test]
+    fn test_classify_expiry_from_google() {
+        let conf = TokenHealthConf::default();
+        let now = Utc::now();
+        let day = 24 * 60 * 60;
+        let (status, _) = classify(&refreshed(Some(10 * day)), None, now, &conf);
+        assert_eq!(status, TokenStatus::Valid);
+        let (status, _) = classify(&refreshed(Some(day)), None, now, &conf);
+        assert_eq!(status, TokenStatus::Expiring);
+    }
+
+    #[test]
+    fn test_classify_expiry_from_lifetime() {
+        let conf = TokenHealthConf {
+            refresh_token_lifetime_days: Some(7),
+            ..TokenHealthConf::default()
+        };
+        let now = Utc::now();
+        let (status, _) = classify(&refreshed(None), Some(now - Duration::days(1)), now, &conf);
+        assert_eq!(status, TokenStatus::Valid);
+        let (status, _) = classify(&refreshed(None), Some(now - Duration::days(6)), now, &conf);
+        assert_eq!(status, TokenStatus::Expiring);
+    }
+
+    #[test]
+    fn test_classify_failed_refresh() {
+        let conf = TokenHealthConf::default();
+        let now = Utc::now();
+        let revoked = TokenCheck::Revoked("Token has been expired or revoked.".to_string());
+        assert_eq!(classify(&revoked, None, now, &conf).0, TokenStatus::Revoked);
+        let missing = TokenCheck::Missing("no refresh token".to_string());
+        assert_eq!(classify(&missing, None, now, &conf).0, TokenStatus::Missing);
+    }
+}